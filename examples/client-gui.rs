@@ -1,13 +1,14 @@
 use eframe::egui;
 use egui::{Color32, Key, PointerButton, Rgba, RichText, Ui};
 use mygem::{
+    gemtext::Document,
     uri::{Uri, UriOwned},
     *,
 };
 use std::sync::{Arc, Mutex, mpsc::channel};
 
 struct State {
-    page_content: String,
+    page: Document,
     processing: bool,
     /// Navigation stack for *simple* back functionalility
     nav: Vec<UriOwned>,
@@ -16,7 +17,7 @@ struct State {
 fn main() -> eframe::Result {
     let (sender, receiver) = channel::<()>();
     let state = Arc::new(Mutex::new(State {
-        page_content: String::new(),
+        page: Document::default(),
         processing: false,
         nav: Vec::new(),
     }));
@@ -26,59 +27,64 @@ fn main() -> eframe::Result {
         move || {
             let client = Client::new();
             while receiver.recv().is_ok() {
-                let mut req_url = {
+                let req_url = {
                     let mut state = state.lock().unwrap();
                     let Some(req_url) = state.nav.last() else {
                         continue;
                     };
-                    let req_url = req_url.to_string();
+                    // Fragments are for the client, not the server.
+                    let req_url = UriOwned {
+                        fragment: None,
+                        ..req_url.clone()
+                    };
                     state.processing = true;
                     req_url
                 };
 
-                let page_content;
-                loop {
-                    if let Ok(request) = Request::new(&req_url) {
-                        match client.send_request(request) {
-                            Ok(response)
-                                if response.header.status == Status::Success
-                                    && response.header.meta().starts_with("text/") =>
-                            {
-                                page_content =
-                                    response.body_as_str().unwrap().to_string();
-                                break;
-                            }
-                            Ok(resp)
-                                if matches!(resp.header.status, Status::Redirect(_)) =>
-                            {
-                                req_url = resp.header.meta().to_string();
-                                eprintln!("Following redirect to \"{}\"", req_url);
-                                continue;
-                            }
-                            Ok(response) => {
-                                page_content = format!(
-                                    "{:?}: {}",
-                                    response.header.status,
-                                    response.header.meta()
-                                );
-                                break;
-                            }
-                            Err(e) => {
-                                page_content = format!(
-                                    "Failed to make request to \"{}\"; {e}",
-                                    request.url_as_str()
-                                );
-                                break;
+                let mut final_url = None;
+                let page = match Request::try_from(&req_url) {
+                    Ok(request) => match client.send_request_following(request, 5).map(
+                        |mut response| {
+                            final_url = response.url.take();
+                            response
+                        },
+                    ) {
+                        Ok(response)
+                            if response.header.mime().is_some_and(|x| x.is_text()) =>
+                        {
+                            if response.header.mime().is_some_and(|x| x.is_gemtext()) {
+                                response.into_gemtext_document().unwrap_or_default()
+                            } else {
+                                Document::new(&response.decoded_body())
                             }
                         }
-                    } else {
-                        page_content = "Invalid request URL!".to_string();
-                        break;
-                    };
-                }
+                        Ok(response) => Document::new(&format!(
+                            "{}: {}",
+                            response.header.status,
+                            response.header.meta()
+                        )),
+                        Err(e) => Document::new(&format!(
+                            "Failed to make request to \"{req_url}\"; {e}"
+                        )),
+                    },
+                    Err(_) => Document::new("Invalid request URL!"),
+                };
 
                 let mut state = state.lock().unwrap();
-                state.page_content = page_content;
+                // Relative links on a page we were redirected to are relative to
+                // where the redirects ended, so that's what goes on the stack.
+                if let (Some(url), Some(last)) = (final_url, state.nav.last_mut())
+                    && (UriOwned {
+                        fragment: None,
+                        ..last.clone()
+                    }) == req_url
+                {
+                    *last = UriOwned {
+                        fragment: last.fragment.take(),
+                        ..url
+                    };
+                }
+                state.page = page;
                 state.processing = false;
             }
         }
@@ -91,6 +97,7 @@ fn main() -> eframe::Result {
 
     // Our application state:
     let mut search_bar_text = "".to_owned();
+    let mut linkify = false;
 
     eframe::run_simple_native("Gemini Client", options, move |ctx, _frame| {
         let mut state = state.lock().unwrap();
@@ -121,6 +128,7 @@ fn main() -> eframe::Result {
                         sender.send(()).unwrap();
                     }
                 });
+                ui.checkbox(&mut linkify, "Linkify");
                 if state.processing {
                     ui.add(egui::Spinner::new());
                 }
@@ -128,11 +136,9 @@ fn main() -> eframe::Result {
         });
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
-                if let Some(navto) = render_gemtext(
-                    ui,
-                    Gemtext::new(&state.page_content),
-                    state.nav.last(),
-                ) {
+                if let Some(navto) =
+                    render_gemtext(ui, &state.page, state.nav.last(), linkify)
+                {
                     search_bar_text = navto.to_string();
                     state.nav.push(navto);
                     sender.send(()).unwrap();
@@ -143,17 +149,38 @@ fn main() -> eframe::Result {
 }
 
 /// Optionally returns a url to navigate to. This handles rendered links.
+///
+/// With `linkify` set, bare URLs in (non-preformatted) text lines are rendered
+/// as links as well.
 fn render_gemtext(
     ui: &mut Ui,
-    gemtext: Gemtext,
+    document: &Document,
     last_path: Option<&UriOwned>,
+    linkify: bool,
 ) -> Option<UriOwned> {
     let mut navto = None;
-    for line in gemtext {
+    for line in document.iter() {
         match line {
-            GemtextToken::Text(text, pre) => {
-                if pre.preformatted {
-                    ui.label(RichText::new(text).monospace().code());
+            GemtextToken::Preformatted(text, _alt) => {
+                ui.label(RichText::new(text).monospace().code());
+            }
+            GemtextToken::Text(text) => {
+                if linkify {
+                    ui.horizontal_wrapped(|ui| {
+                        ui.spacing_mut().item_spacing.x = 0.0;
+                        for span in gemtext::linkify(text) {
+                            match span {
+                                gemtext::TextSpan::Text(text) => {
+                                    ui.label(text);
+                                }
+                                gemtext::TextSpan::Url(url) => {
+                                    if ui.link(url).clicked() {
+                                        navto = Uri::new(url).ok().map(UriOwned::from);
+                                    }
+                                }
+                            }
+                        }
+                    });
                 } else {
                     ui.label(text);
                 }
@@ -171,46 +198,23 @@ fn render_gemtext(
                 );
             }
             GemtextToken::Link(link, text) => {
-                // Pages may use relative links which aren't valid URLs, so these must be
-                // corrected.
+                // Pages may use relative links, which are resolved against the
+                // page's URL.
                 let Ok(url) = Uri::new(link) else {
                     ui.label(link);
                     continue;
                 };
-                if url.host.is_none() {
-                    let mut url = UriOwned::from(url);
-                    let (mut path, dir) = if let Some(current_path) = last_path {
-                        url.host = current_path.host.clone();
-                        let p = current_path.path.as_deref().unwrap_or("/");
-                        (std::path::PathBuf::from(p), p.ends_with('/'))
-                    } else {
-                        (std::path::PathBuf::new(), true)
-                    };
-                    if let Some(p) =
-                        url.path.as_deref().map(|x| x.trim_start_matches('/'))
-                    {
-                        if !dir {
-                            path.pop();
-                        }
-                        path.push(p);
-                    }
-                    url.path = Some(path.to_str().unwrap().to_string());
-                    url.scheme = url.scheme.or_else(|| Some("gemini".to_string()));
-                    if match text {
-                        Some(text) => ui.link(text),
-                        None => ui.link(link),
-                    }
-                    .clicked()
-                    {
-                        navto = Some(url);
-                    };
-                } else if match text {
+                let url = match last_path {
+                    Some(base) => url.resolve(&Uri::from(base)),
+                    None => url.into(),
+                };
+                if match text {
                     Some(text) => ui.link(text),
                     None => ui.link(link),
                 }
                 .clicked()
                 {
-                    navto = Some(url.into());
+                    navto = Some(url);
                 }
             }
         }
@@ -4,7 +4,7 @@ use std::io::Write;
 fn main() {
     let mut args = std::env::args().skip(1);
     let url = args.next().expect("Expected URL");
-    let mut request = match Request::new(&url) {
+    let request = match Request::new(&url) {
         Ok(r) => r,
         Err(e) => {
             eprintln!("Invalid request: {e}");
@@ -15,36 +15,18 @@ fn main() {
 
     let client = Client::new();
 
-    let mut response: Response;
-    // Loop to follow redirects
-    loop {
-        match client.send_request(request) {
-            Ok(r) => {
-                response = r;
-            }
-            Err(e) => {
-                eprintln!("Failed to get response: {e}");
-                std::process::exit(1);
-            }
-        };
-        if matches!(response.header.status, Status::Redirect(_)) {
-            eprintln!("Following redirect: {}", response.header.meta());
-            request = match Request::new(response.header.meta()) {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("Invalid request: {e}");
-                    std::process::exit(1);
-                }
-            };
-        } else {
-            break;
+    let response = match client.send_request_following(request, 5) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Failed to get response: {e}");
+            std::process::exit(1);
         }
-    }
+    };
 
     if response.header.status != Status::Success {
         eprintln!(
-            "Recived error response from url: {}\n{:?}: {}",
-            request.url_as_str(),
+            "Recived error response from url: {}\n{}: {}",
+            url,
             response.header.status,
             response.header.meta()
         );
@@ -52,14 +34,18 @@ fn main() {
     }
 
     let meta = response.header.meta();
-    if meta.starts_with("text/") {
-        println!("{}", response.body_as_str().expect("expected utf8 text"));
+    if response.header.mime().is_some_and(|mime| mime.is_text()) {
+        println!("{}", response.decoded_body());
     } else {
-        let path =
-            std::path::PathBuf::from("/tmp").join(request.url().path.unwrap_or(""));
+        // Named after the URL the redirects ended on, not the one asked for.
+        let name = match &response.url {
+            Some(url) => response.suggested_filename(&url.as_ref()),
+            None => "index".to_string(),
+        };
+        let path = std::path::PathBuf::from("/tmp").join(name);
         eprintln!("Saving data with mimetype '{}' to {:?}", meta, path);
         let mut f = std::fs::File::create(&path).unwrap();
-        f.write_all(&response.body)
+        f.write_all(response.as_ref())
             .expect("failed to write to file!");
     }
 }
@@ -1,12 +1,16 @@
 #![allow(clippy::literal_string_with_formatting_args)]
 
+use std::borrow::Cow;
 use std::io::{self, Read};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub use status::Status;
 
+/// A string of at most `N` bytes stored inline, e.g. for validating the 1024
+/// byte request URLs and META strings of the protocol without allocating.
 #[derive(Clone, Copy)]
-struct StackStr<const N: usize> {
+pub struct StackStr<const N: usize> {
     buf: [u8; N],
     len: usize,
 }
@@ -18,24 +22,28 @@ impl<const N: usize> std::fmt::Debug for StackStr<N> {
 }
 
 impl<const N: usize> StackStr<N> {
-    fn from_str(s: &str) -> Option<Self> {
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[0..self.len]).unwrap()
+    }
+}
+
+impl<const N: usize> std::str::FromStr for StackStr<N> {
+    type Err = &'static str;
+    /// Fails if `s` is longer than `N` bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut buf = [0u8; N];
         if s.len() > N {
-            return None;
+            return Err("string is too long");
         }
         buf[0..s.len()].copy_from_slice(&s.as_bytes()[0..s.len()]);
-        Some(Self { buf, len: s.len() })
-    }
-
-    fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.buf[0..self.len]).unwrap()
+        Ok(Self { buf, len: s.len() })
     }
 }
 
 impl<const N: usize> TryFrom<&str> for StackStr<N> {
     type Error = &'static str;
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        Self::from_str(s).ok_or("string is too long")
+        s.parse()
     }
 }
 
@@ -66,8 +74,16 @@ pub enum RequestError {
     Io(#[from] io::Error),
     #[error("URL was longer than 1024 bytes")]
     UrlTooLong,
+    /// The URL was longer than a limit passed to [`Request::read_with_limit`],
+    /// but within the protocol's.
+    #[error("URL was longer than the limit of {0} bytes")]
+    UrlOverLimit(usize),
     #[error("URL is not a valid gemini URI")]
     InvalidUrl,
+    #[error("URI: {0}")]
+    InvalidUri(#[from] uri::Error),
+    #[error("Not a valid Gemini request URL: {0}")]
+    NotGemini(#[from] uri::GeminiUriError),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -75,29 +91,149 @@ pub struct Request {
     uri: StackStr<1024>,
 }
 
+impl TryFrom<uri::Uri<'_>> for Request {
+    type Error = RequestError;
+    fn try_from(uri: uri::Uri) -> Result<Self, Self::Error> {
+        Self::from_uri(uri)
+    }
+}
+
+impl TryFrom<&uri::UriOwned> for Request {
+    type Error = RequestError;
+    fn try_from(uri: &uri::UriOwned) -> Result<Self, Self::Error> {
+        Self::from_uri(uri.into())
+    }
+}
+
 impl Request {
+    /// Validates `uri` as a Gemini request URL. A URL without a scheme, like
+    /// `//example.com/`, is taken to be `gemini`.
     pub fn new(uri: impl AsRef<str>) -> Result<Self, RequestError> {
         let uri = uri.as_ref();
         if uri.len() > 1024 {
             return Err(RequestError::UrlTooLong);
         }
-        let view = uri::Uri::new(uri).map_err(|_| RequestError::UrlTooLong)?;
-        // SEE: 1.2 Gemini URI scheme
-        if uri.starts_with('\u{FEFF}') || view.host.is_none() || view.userinfo.is_some() {
+        if uri.starts_with('\u{FEFF}') {
             return Err(RequestError::InvalidUrl);
         };
+        let parsed = uri::Uri::new(uri)?;
+        parsed.validate_gemini_request()?;
+        let uri = match parsed.scheme {
+            Some(_) => Cow::Borrowed(uri),
+            None => Cow::Owned(format!("gemini:{uri}")),
+        };
+        if uri.len() > 1024 {
+            return Err(RequestError::UrlTooLong);
+        }
+        let uri = uri.as_ref();
         Ok(Self {
             uri: uri.try_into().expect("I checked the length"),
         })
     }
-    pub fn url(&self) -> uri::Uri {
+    /// Builds the follow-up request to an input prompt (status `10`/`11`) by
+    /// sending the percent-encoded `input` as the query of `base`. Any query or
+    /// fragment already on `base` is dropped.
+    pub fn from_input(base: &uri::Uri, input: &str) -> Result<Self, RequestError> {
+        let query = uri::percent_encode(input);
+        let uri = uri::Uri {
+            query: Some(&query),
+            fragment: None,
+            ..*base
+        };
+        Self::new(uri.to_string())
+    }
+    /// Checks an already parsed URL like [`Request::new`] does, without
+    /// parsing it again.
+    ///
+    /// The components of a [`UriOwned`](uri::UriOwned) can be set to anything,
+    /// so ones containing control characters or spaces are rejected with
+    /// [`RequestError::InvalidUrl`], which keeps e.g. a CR/LF out of the
+    /// request line.
+    fn from_uri(uri: uri::Uri) -> Result<Self, RequestError> {
+        let components = [
+            uri.scheme,
+            uri.userinfo,
+            uri.host,
+            uri.port,
+            uri.path,
+            uri.query,
+            uri.fragment,
+        ];
+        if components
+            .into_iter()
+            .flatten()
+            .any(|x| x.contains(|c: char| c.is_ascii_control() || c == ' '))
+        {
+            return Err(RequestError::InvalidUrl);
+        }
+        uri.validate_gemini_request()?;
+        let uri = uri::Uri {
+            scheme: uri.scheme.or(Some("gemini")),
+            ..uri
+        };
+        Ok(Self {
+            uri: uri
+                .to_string()
+                .parse()
+                .map_err(|_| RequestError::UrlTooLong)?,
+        })
+    }
+    /// Builds the follow-up request to an input prompt for this request's URL,
+    /// see [`Request::from_input`].
+    pub fn with_query(&self, input: &str) -> Result<Self, RequestError> {
+        Self::from_input(&self.url(), input)
+    }
+    pub fn url(&self) -> uri::Uri<'_> {
         uri::Uri::new(self.uri.as_str()).unwrap()
     }
     pub fn url_as_str(&self) -> &str {
         self.uri.as_str()
     }
-    pub fn read<R: std::io::Read>(_reader: R) -> Option<Self> {
-        unimplemented!();
+    /// Returns the percent-decoded query, which is how a capsule receives the
+    /// user's answer to an input prompt.
+    ///
+    /// An empty query (`gemini://host/path?`) is `Some("")`, while no query at
+    /// all is `None`. A query with a malformed escape (`?50%`) is returned as
+    /// it was sent.
+    pub fn search_query(&self) -> Option<String> {
+        self.url()
+            .query
+            .map(|query| uri::percent_decode(query).unwrap_or_else(|| query.to_string()))
+    }
+    /// Reads a request line (`<URL><CR><LF>`) as sent by [`Request::write`],
+    /// e.g. off a server's socket.
+    ///
+    /// Nothing past the CR/LF is consumed, which means reading a byte at a time,
+    /// so consider buffering `reader`.
+    pub fn read<R: std::io::Read>(reader: R) -> Result<Self, RequestError> {
+        Self::read_with_limit(reader, 1024)
+    }
+    /// Like [`Request::read`], but gives up with
+    /// [`RequestError::UrlOverLimit`] as soon as the URL is longer than
+    /// `max_bytes`, for servers that want to be stricter than the protocol's
+    /// 1024 bytes. A larger limit is no different from [`Request::read`].
+    pub fn read_with_limit<R: std::io::Read>(
+        mut reader: R,
+        max_bytes: usize,
+    ) -> Result<Self, RequestError> {
+        let limit = max_bytes.min(1024);
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            reader.read_exact(&mut byte)?;
+            line.push(byte[0]);
+            if line.ends_with(b"\r\n") {
+                break;
+            } else if line.len() - usize::from(byte[0] == b'\r') > limit {
+                return Err(match limit {
+                    1024 => RequestError::UrlTooLong,
+                    _ => RequestError::UrlOverLimit(limit),
+                });
+            }
+        }
+        let uri = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| RequestError::InvalidUrl)?;
+        Self::new(uri)
     }
     pub fn write<W: std::io::Write>(&self, mut writer: W) -> Result<(), RequestError> {
         writer.write_all(self.uri.as_bytes())?;
@@ -106,6 +242,100 @@ impl Request {
     }
 }
 
+/// An upload with the Titan protocol, Gemini's companion for editing
+/// capsules: the same TLS transport, but the request line carries the size
+/// and media type of a body that follows it.
+///
+/// ```not_rust
+/// titan://example.com/page.gmi;size=5;mime=text/gemini;token=secret<CR><LF>
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitanRequest {
+    url: String,
+    mime: Option<String>,
+    token: Option<String>,
+}
+
+impl TitanRequest {
+    /// Fails with [`GeminiUriError::WrongScheme`](uri::GeminiUriError::WrongScheme)
+    /// unless `url` is a `titan` URL, and otherwise like [`Request::new`] on
+    /// anything a Gemini request URL couldn't have either.
+    pub fn new(url: impl AsRef<str>) -> Result<Self, RequestError> {
+        let url = url.as_ref();
+        if url.starts_with('\u{FEFF}') {
+            return Err(RequestError::InvalidUrl);
+        }
+        let parsed = uri::Uri::new(url)?;
+        if !parsed
+            .scheme
+            .is_some_and(|x| x.eq_ignore_ascii_case("titan"))
+        {
+            return Err(uri::GeminiUriError::WrongScheme.into());
+        }
+        // Apart from the scheme, the rules are the same as for Gemini.
+        uri::Uri {
+            scheme: Some("gemini"),
+            ..parsed
+        }
+        .validate_gemini_request()?;
+        Ok(Self {
+            url: url.to_string(),
+            mime: None,
+            token: None,
+        })
+    }
+
+    /// The media type of the body. Servers assume `text/gemini` without one.
+    pub fn mime(mut self, mime: &str) -> Self {
+        self.mime = Some(mime.to_string());
+        self
+    }
+
+    /// A token the server requires to accept the upload, like a password.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    pub fn url(&self) -> uri::Uri<'_> {
+        uri::Uri::new(&self.url).unwrap()
+    }
+
+    /// The request line for a body of `size` bytes, without the CR/LF. Like a
+    /// Gemini request line, it can't be longer than 1024 bytes.
+    ///
+    /// The parameters go at the end of the path, so any query comes after
+    /// them: `titan://host/a;size=5?q`.
+    pub fn line(&self, size: usize) -> Result<String, RequestError> {
+        let url = self.url();
+        // Without a path the parameters would run into the authority.
+        let path = url.path.filter(|x| !x.is_empty()).or(Some("/"));
+        let mut line = uri::Uri {
+            path,
+            query: None,
+            ..url
+        }
+        .to_string();
+        line.push_str(&format!(";size={size}"));
+        if let Some(mime) = &self.mime {
+            line.push_str(";mime=");
+            line.push_str(&uri::percent_encode_set(mime, b"/+"));
+        }
+        if let Some(token) = &self.token {
+            line.push_str(";token=");
+            line.push_str(&uri::percent_encode(token));
+        }
+        if let Some(query) = url.query {
+            line.push('?');
+            line.push_str(query);
+        }
+        if line.len() > 1024 {
+            return Err(RequestError::UrlTooLong);
+        }
+        Ok(line)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ResponseHeader {
     pub status: Status,
@@ -113,15 +343,58 @@ pub struct ResponseHeader {
 }
 
 impl ResponseHeader {
-    pub fn new(status: Status, meta: &str) -> Result<Self, &'static str> {
+    /// The length of the longest valid header,
+    /// `<STATUS><SPACE><META><CR><LF>`.
+    const MAX_LEN: usize = 2 + 1 + 1024 + 2;
+
+    /// Fails like [`ResponseHeader::parse`] would on a header with this META,
+    /// if it's longer than 1024 bytes, starts with U+FEFF or contains control
+    /// characters.
+    pub fn new(status: Status, meta: &str) -> Result<Self, ResponseHeaderParseError> {
         Ok(Self {
             status,
-            meta: StackStr::from_str(meta)
-                .ok_or("meta can be no longer than 1024 bytes")?,
+            meta: Self::check_meta(meta, false)?,
+        })
+    }
+
+    fn check_meta(
+        meta: &str,
+        lenient: bool,
+    ) -> Result<StackStr<1024>, ResponseHeaderParseError> {
+        if meta.starts_with('\u{FEFF}') {
+            return Err(ResponseHeaderParseError::Malformed(
+                "META starts with U+FEFF",
+            ));
+        }
+        if !lenient && meta.chars().any(char::is_control) {
+            return Err(ResponseHeaderParseError::Malformed(
+                "META contains control characters",
+            ));
+        }
+        meta.parse().map_err(|_| {
+            ResponseHeaderParseError::Malformed("META is be longer than 1024 bytes")
         })
     }
+
+    /// Parses a `<STATUS><SPACE><META><CR><LF>` header. Anything after the
+    /// CR/LF is ignored.
+    ///
+    /// META containing control characters, e.g. a stray CR or a tab, is
+    /// [`Malformed`](ResponseHeaderParseError::Malformed); see
+    /// [`ResponseHeader::parse_lenient`] for capsules that send them anyway.
     pub fn parse(src: impl AsRef<[u8]>) -> Result<Self, ResponseHeaderParseError> {
-        let src = src.as_ref();
+        Self::parse_with(src.as_ref(), false)
+    }
+
+    /// Like [`ResponseHeader::parse`], but lets control characters in META
+    /// through.
+    pub fn parse_lenient(
+        src: impl AsRef<[u8]>,
+    ) -> Result<Self, ResponseHeaderParseError> {
+        Self::parse_with(src.as_ref(), true)
+    }
+
+    fn parse_with(src: &[u8], lenient: bool) -> Result<Self, ResponseHeaderParseError> {
         let src = std::str::from_utf8(src)
             .map_err(|_| ResponseHeaderParseError::Malformed("is not valid UTF-8"))?;
         if src.starts_with('\u{FEFF}') {
@@ -148,23 +421,51 @@ impl ResponseHeader {
             .map_err(|_| ResponseHeaderParseError::Malformed("invalid status code"))?;
         let status = status::Status::try_from(status)?;
 
-        if meta.len() > 1024 {
-            return Err(ResponseHeaderParseError::Malformed(
-                "META is be longer than 1024 bytes",
-            ));
-        } else if meta.starts_with('\u{FEFF}') {
-            return Err(ResponseHeaderParseError::Malformed(
-                "META starts with U+FEFF",
-            ));
-        }
-
         Ok(Self {
             status,
-            meta: StackStr::from_str(meta)
-                .expect("We checked that `meta` fits within 1024"),
+            meta: Self::check_meta(meta, lenient)?,
         })
     }
 
+    /// Reads a header off `reader`, leaving it positioned at the start of the
+    /// body.
+    ///
+    /// Nothing past the CR/LF is consumed, which means reading a byte at a time,
+    /// so consider buffering `reader`. Once more bytes than the longest valid
+    /// header have arrived without a CR/LF this gives up with
+    /// [`ResponseHeaderParseError::Malformed`] rather than buffering forever.
+    pub fn read<R: io::Read>(reader: R) -> Result<Self, ResponseReadError> {
+        Self::read_with(reader, false)
+    }
+
+    fn read_with<R: io::Read>(
+        mut reader: R,
+        lenient: bool,
+    ) -> Result<Self, ResponseReadError> {
+        let mut buffer = Vec::new();
+        let mut byte = [0];
+        while !buffer.ends_with(b"\r\n") {
+            if buffer.len() == Self::MAX_LEN {
+                return Err(ResponseHeaderParseError::Malformed("header too long").into());
+            }
+            match reader.read_exact(&mut byte) {
+                Ok(()) => buffer.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(ResponseReadError::MissingHeader);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Self::parse_with(&buffer, lenient)?)
+    }
+
+    /// Writes the header as `<STATUS><SPACE><META><CR><LF>`, the inverse of
+    /// [`ResponseHeader::parse`]. META can't be longer than 1024 bytes, which
+    /// [`ResponseHeader::new`] already made sure of.
+    pub fn write<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        write!(writer, "{:02} {}\r\n", self.status.code(), self.meta())
+    }
+
     pub fn meta(&self) -> &str {
         self.meta.as_ref()
     }
@@ -172,11 +473,27 @@ impl ResponseHeader {
     pub fn status(&self) -> Status {
         self.status
     }
+
+    /// Parses the META of a successful response as the body's media type.
+    ///
+    /// An empty META means `text/gemini; charset=utf-8`. `None` for other
+    /// statuses or a META that isn't a media type.
+    pub fn mime(&self) -> Option<MediaType> {
+        if self.status != Status::Success {
+            return None;
+        }
+        // SEE: 3.3.3 an empty MIME type defaults to text/gemini
+        match self.meta().trim() {
+            "" => MediaType::parse("text/gemini; charset=utf-8"),
+            meta => MediaType::parse(meta),
+        }
+    }
 }
 
+/// Formats the header the way it's sent, without the CRLF, e.g. `51 Not here`.
 impl std::fmt::Display for ResponseHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{:?} {}", self.status, self.meta.as_str())
+        write!(f, "{} {}", self.status.code(), self.meta.as_str())
     }
 }
 
@@ -188,49 +505,370 @@ pub enum ResponseReadError {
     Io(#[from] io::Error),
     #[error("Couldn't parse a response header as there was nothing to parse")]
     MissingHeader,
+    #[error("Body was larger than the limit of {0} bytes")]
+    BodyTooLarge(usize),
+}
+
+/// A response that wasn't a success or input prompt, returned by
+/// [`Response::into_result`].
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ResponseFailure {
+    /// The resource is elsewhere, at the URL in `meta`.
+    #[error("Redirected to {meta}")]
+    Redirect { status: Status, meta: String },
+    /// A temporary, permanent or client certificate failure.
+    #[error("{status}: {meta}")]
+    Failure { status: Status, meta: String },
+}
+
+/// Returned by [`Response::verify_length`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Expected a body of {expected} bytes, but got {actual}")]
+pub struct BodyLengthError {
+    pub expected: usize,
+    pub actual: usize,
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub header: ResponseHeader,
     pub body: Vec<u8>,
+    /// When the response was received. This is set by [`Client`], but not by
+    /// [`Response::read`] which doesn't know where its bytes came from.
+    pub received_at: Option<std::time::SystemTime>,
+    /// The certificate the server identified itself with, e.g. to show the
+    /// user which identity was trusted on first use. Like
+    /// [`received_at`](Self::received_at) this is only set by [`Client`].
+    pub peer_certificate: Option<CertificateDer<'static>>,
+    /// The URL that was requested, which after
+    /// [`Client::send_request_following`] is the one the redirects ended on,
+    /// e.g. to resolve the page's relative links against. Like
+    /// [`received_at`](Self::received_at) this is only set by [`Client`].
+    pub url: Option<uri::UriOwned>,
+    /// Set by [`Client`] when the server closed the connection without a TLS
+    /// close_notify. Plenty of servers do, so the response is still returned,
+    /// but the body may have been cut short, e.g. by an attacker resetting
+    /// the connection.
+    pub truncated: bool,
 }
 
 impl Response {
     pub fn read<R: io::Read>(reader: R) -> Result<Self, ResponseReadError> {
-        let mut header = None;
-        let mut buffer = Vec::new();
-        let mut saw_cr = false;
+        Self::read_limited(reader, usize::MAX)
+    }
 
-        for byte in reader.bytes() {
-            let byte = byte?;
-            buffer.push(byte);
+    /// Like [`Response::read`], but gives up with
+    /// [`ResponseReadError::BodyTooLarge`] once the body grows past
+    /// `max_body` bytes, rather than letting a hostile server use up all of
+    /// the memory.
+    pub fn read_limited<R: io::Read>(
+        reader: R,
+        max_body: usize,
+    ) -> Result<Self, ResponseReadError> {
+        Self::read_with(reader, max_body, false)
+    }
 
-            if header.is_none() {
-                if saw_cr && byte == b'\n' {
-                    // We're done with the header.
-                    header = Some(ResponseHeader::parse(&buffer)?);
-                    buffer.clear();
-                }
-                saw_cr = byte == b'\r';
-            }
+    /// [`Response::read_limited`], parsing the header leniently if asked to,
+    /// see [`ResponseHeader::parse_lenient`].
+    fn read_with<R: io::Read>(
+        mut reader: R,
+        max_body: usize,
+        lenient: bool,
+    ) -> Result<Self, ResponseReadError> {
+        let header = ResponseHeader::read_with(&mut reader, lenient)?;
+        let mut body = Vec::new();
+        reader
+            .take(
+                u64::try_from(max_body)
+                    .unwrap_or(u64::MAX)
+                    .saturating_add(1),
+            )
+            .read_to_end(&mut body)?;
+        if body.len() > max_body {
+            return Err(ResponseReadError::BodyTooLarge(max_body));
         }
-        let header = header.ok_or(ResponseReadError::MissingHeader)?;
 
         Ok(Self {
             header,
-            body: buffer,
+            body,
+            received_at: None,
+            peer_certificate: None,
+            url: None,
+            truncated: false,
         })
     }
 
+    /// Reads just the header, handing `reader` back positioned at the start
+    /// of the body, so that a large body can be copied to a file as it
+    /// arrives instead of being held in memory.
+    ///
+    /// See [`ResponseHeader::read`], which this reads the header with, on
+    /// buffering.
+    pub fn read_streaming<R: io::Read>(
+        mut reader: R,
+    ) -> Result<(ResponseHeader, R), ResponseReadError> {
+        let header = ResponseHeader::read(&mut reader)?;
+        Ok((header, reader))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Reads a complete response that's already in memory.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ResponseReadError> {
+        Self::read(io::Cursor::new(bytes))
+    }
+
+    /// Turns failures into errors so they can be propagated with `?`.
+    ///
+    /// Success and input responses are `Ok`. Redirects aren't failures as
+    /// such, but they aren't the resource either, so they're
+    /// [`ResponseFailure::Redirect`] for callers to follow or give up on.
+    pub fn into_result(self) -> Result<Self, ResponseFailure> {
+        let status = self.header.status;
+        let meta = || self.header.meta().to_string();
+        match status {
+            Status::Success | Status::Input(_) => Ok(self),
+            Status::Redirect(_) => Err(ResponseFailure::Redirect {
+                status,
+                meta: meta(),
+            }),
+            _ => Err(ResponseFailure::Failure {
+                status,
+                meta: meta(),
+            }),
+        }
+    }
+
+    /// The SHA-256 fingerprint of [`peer_certificate`](Self::peer_certificate),
+    /// as pinned by [`tofu::TofuVerifier`].
+    pub fn fingerprint_sha256(&self) -> Option<tofu::Fingerprint> {
+        self.peer_certificate.as_ref().map(tofu::fingerprint)
+    }
+
+    /// Whether `other` has the same status, META and body, ignoring when each
+    /// was received. Gemini has no ETags, so e.g. a feed reader can use this
+    /// to tell whether a page changed since it was last fetched.
+    pub fn same_content(&self, other: &Response) -> bool {
+        self.header.status == other.header.status
+            && self.header.meta() == other.header.meta()
+            && self.body == other.body
+    }
+
+    /// Checks that the body is exactly `expected` bytes long, for extensions
+    /// that declare the length of what they send up front. Plain Gemini
+    /// doesn't, so this is never checked while reading.
+    pub fn verify_length(&self, expected: usize) -> Result<(), BodyLengthError> {
+        if self.body.len() != expected {
+            return Err(BodyLengthError {
+                expected,
+                actual: self.body.len(),
+            });
+        }
+        Ok(())
+    }
+
     pub fn body_as_str(&self) -> Result<&str, std::str::Utf8Error> {
         std::str::from_utf8(&self.body)
     }
+
+    /// Decodes the body as text in the charset named by the META, UTF-8 if
+    /// there isn't one.
+    ///
+    /// UTF-8, US-ASCII and ISO-8859-1 are understood; anything else is decoded
+    /// as UTF-8. Bytes that aren't valid in the charset become U+FFFD.
+    pub fn decoded_body(&self) -> Cow<'_, str> {
+        let charset = self
+            .media_type()
+            .and_then(|mime| mime.charset().map(str::to_ascii_lowercase));
+        match charset.as_deref() {
+            Some("iso-8859-1" | "iso_8859-1" | "latin1" | "l1") => {
+                // The first 256 code points are exactly ISO-8859-1.
+                Cow::Owned(self.body.iter().copied().map(char::from).collect())
+            }
+            Some("us-ascii" | "ascii") if !self.body.is_ascii() => Cow::Owned(
+                self.body
+                    .iter()
+                    .map(|&x| {
+                        if x.is_ascii() {
+                            char::from(x)
+                        } else {
+                            '\u{FFFD}'
+                        }
+                    })
+                    .collect(),
+            ),
+            _ => String::from_utf8_lossy(&self.body),
+        }
+    }
+
+    /// Parses the body into a [`gemtext::Document`], if this is a successful
+    /// `text/gemini` response with a UTF-8 body.
+    pub fn into_gemtext_document(self) -> Option<gemtext::Document> {
+        if !self.is_gemtext() {
+            return None;
+        }
+        let body = String::from_utf8(self.body).ok()?;
+        Some(gemtext::Document::new(&body))
+    }
+
+    fn is_gemtext(&self) -> bool {
+        self.header.mime().is_some_and(|mime| mime.is_gemtext())
+    }
+
+    /// The media type of a successful response's body, see
+    /// [`ResponseHeader::mime`].
+    pub fn media_type(&self) -> Option<MediaType> {
+        self.header.mime()
+    }
+
+    /// A file name to save the body of this response to `url` under, e.g.
+    /// `cat.png` for an `image/png` from `gemini://example.com/pics/cat`.
+    ///
+    /// The name is the last segment of the URL's path, or `index` if there
+    /// isn't one, with its extension replaced by the media type's
+    /// [preferred extension](MediaType::preferred_extension) when there is
+    /// one. It never contains a path separator.
+    pub fn suggested_filename(&self, url: &uri::Uri) -> String {
+        let name = url
+            .path
+            .and_then(|path| path.rsplit('/').next())
+            .map(|name| uri::percent_decode(name).unwrap_or_else(|| name.to_string()))
+            .map(|name| name.replace(['/', '\\'], "_"))
+            .filter(|name| !matches!(name.as_str(), "" | "." | ".."))
+            .unwrap_or_else(|| "index".to_string());
+        let Some(extension) = self.media_type().and_then(|m| m.preferred_extension())
+        else {
+            return name;
+        };
+        let stem = match name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => &name,
+        };
+        format!("{stem}.{extension}")
+    }
+}
+
+/// A media (MIME) type such as `text/gemini; charset=utf-8`, which a successful
+/// response carries as its META.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    /// The top-level type, e.g. `text`. Always lowercase.
+    pub type_: String,
+    /// The subtype, e.g. `gemini`. Always lowercase.
+    pub subtype: String,
+    /// The parameters after the type in order, e.g. `("charset", "utf-8")`.
+    /// Names are lowercase and quotes around values are removed.
+    pub parameters: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// Parses `type/subtype` followed by any number of `; name=value`
+    /// parameters. Parameters without a `=` are skipped.
+    pub fn parse(src: &str) -> Option<Self> {
+        let mut parts = src.split(';');
+        let (type_, subtype) = parts.next()?.trim().split_once('/')?;
+        if type_.is_empty() || subtype.is_empty() {
+            return None;
+        }
+        let parameters = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|x| x.strip_suffix('"'))
+                    .unwrap_or(value);
+                Some((name.trim().to_ascii_lowercase(), value.to_string()))
+            })
+            .collect();
+        Some(Self {
+            type_: type_.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            parameters,
+        })
+    }
+
+    /// The value of the parameter called `name`, ignoring case.
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The `charset` parameter, which for `text/*` types defaults to `utf-8`
+    /// when it's absent.
+    pub fn charset(&self) -> Option<&str> {
+        match self.parameter("charset") {
+            None if self.type_ == "text" => Some("utf-8"),
+            charset => charset,
+        }
+    }
+
+    /// The `lang` parameter of a `text/gemini` body, e.g. `en` or `fr,de`.
+    pub fn lang(&self) -> Option<&str> {
+        self.parameter("lang")
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.type_ == "text"
+    }
+
+    pub fn is_gemtext(&self) -> bool {
+        self.type_ == "text" && self.subtype == "gemini"
+    }
+
+    /// The usual file extension, without the dot, for common media types.
+    pub fn preferred_extension(&self) -> Option<&'static str> {
+        let extension = match (self.type_.as_str(), self.subtype.as_str()) {
+            ("text", "gemini") => "gmi",
+            ("text", "plain") => "txt",
+            ("text", "markdown") => "md",
+            ("text", "html") => "html",
+            ("text", "css") => "css",
+            ("text", "csv") => "csv",
+            ("image", "png") => "png",
+            ("image", "jpeg") => "jpg",
+            ("image", "gif") => "gif",
+            ("image", "webp") => "webp",
+            ("image", "svg+xml") => "svg",
+            ("audio", "mpeg") => "mp3",
+            ("audio", "ogg") => "ogg",
+            ("audio", "flac") => "flac",
+            ("video", "mp4") => "mp4",
+            ("video", "webm") => "webm",
+            ("application", "pdf") => "pdf",
+            ("application", "zip") => "zip",
+            ("application", "gzip") => "gz",
+            ("application", "json") => "json",
+            ("application", "xml") => "xml",
+            _ => return None,
+        };
+        Some(extension)
+    }
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}/{}", self.type_, self.subtype)?;
+        for (name, value) in &self.parameters {
+            write!(f, "; {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl AsRef<[u8]> for Response {
+    fn as_ref(&self) -> &[u8] {
+        self.body()
+    }
 }
 
 pub mod status {
     #[derive(Debug, thiserror::Error)]
-    #[error("Status code is not within the acceptable range: {0}")]
+    #[error("Status code {0:02} is outside the range of valid Gemini status codes")]
     pub struct InvalidStatusError(u8);
 
     // TODO: flatten this structure?
@@ -281,10 +919,155 @@ pub mod status {
         }
     }
 
+    /// Formats as the code followed by the name of the status, e.g.
+    /// `51 Not Found`.
+    impl std::fmt::Display for Status {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let name = match self {
+                Self::Input(Input::Input) => "Input",
+                Self::Input(Input::Sensitive) => "Sensitive Input",
+                Self::Success => "Success",
+                Self::Redirect(Redirect::Temporary) => "Temporary Redirect",
+                Self::Redirect(Redirect::Permanent) => "Permanent Redirect",
+                Self::TemporaryFailure(x) => match x {
+                    TemporaryFailure::TemporaryFailure => "Temporary Failure",
+                    TemporaryFailure::ServerUnavailable => "Server Unavailable",
+                    TemporaryFailure::CgiError => "CGI Error",
+                    TemporaryFailure::ProxyError => "Proxy Error",
+                    TemporaryFailure::SlowDown => "Slow Down",
+                },
+                Self::PermanentFailure(x) => match x {
+                    PermanentFailure::PermanentFailure => "Permanent Failure",
+                    PermanentFailure::NotFound => "Not Found",
+                    PermanentFailure::Gone => "Gone",
+                    PermanentFailure::ProxyRequestRefused => "Proxy Request Refused",
+                    PermanentFailure::BadRequest => "Bad Request",
+                },
+                Self::ClientCertificateRequired(x) => match x {
+                    ClientCertificateRequired::ClientCertificateRequired => {
+                        "Client Certificate Required"
+                    }
+                    ClientCertificateRequired::CertificateNotAuthorized => {
+                        "Certificate Not Authorized"
+                    }
+                    ClientCertificateRequired::CertificateNotValid => {
+                        "Certificate Not Valid"
+                    }
+                },
+            };
+            write!(f, "{} {name}", self.code())
+        }
+    }
+
+    impl From<Status> for u8 {
+        fn from(status: Status) -> Self {
+            status.code()
+        }
+    }
+
+    impl Status {
+        /// The status's class, the first digit of its [`code`](Self::code),
+        /// from `1` for input to `6` for client certificates.
+        pub fn category(&self) -> u8 {
+            self.code() / 10
+        }
+
+        pub fn is_input(&self) -> bool {
+            matches!(self, Self::Input(_))
+        }
+
+        pub fn is_success(&self) -> bool {
+            matches!(self, Self::Success)
+        }
+
+        pub fn is_redirect(&self) -> bool {
+            matches!(self, Self::Redirect(_))
+        }
+
+        pub fn is_temporary_failure(&self) -> bool {
+            matches!(self, Self::TemporaryFailure(_))
+        }
+
+        pub fn is_permanent_failure(&self) -> bool {
+            matches!(self, Self::PermanentFailure(_))
+        }
+
+        pub fn is_client_cert_required(&self) -> bool {
+            matches!(self, Self::ClientCertificateRequired(_))
+        }
+
+        /// The two digit code sent on the wire for this status.
+        pub fn code(&self) -> u8 {
+            match self {
+                Self::Input(Input::Input) => 10,
+                Self::Input(Input::Sensitive) => 11,
+                Self::Success => 20,
+                Self::Redirect(Redirect::Temporary) => 30,
+                Self::Redirect(Redirect::Permanent) => 31,
+                Self::TemporaryFailure(x) => match x {
+                    TemporaryFailure::TemporaryFailure => 40,
+                    TemporaryFailure::ServerUnavailable => 41,
+                    TemporaryFailure::CgiError => 42,
+                    TemporaryFailure::ProxyError => 43,
+                    TemporaryFailure::SlowDown => 44,
+                },
+                Self::PermanentFailure(x) => match x {
+                    PermanentFailure::PermanentFailure => 50,
+                    PermanentFailure::NotFound => 51,
+                    PermanentFailure::Gone => 52,
+                    PermanentFailure::ProxyRequestRefused => 53,
+                    PermanentFailure::BadRequest => 59,
+                },
+                Self::ClientCertificateRequired(x) => match x {
+                    ClientCertificateRequired::ClientCertificateRequired => 60,
+                    ClientCertificateRequired::CertificateNotAuthorized => 61,
+                    ClientCertificateRequired::CertificateNotValid => 62,
+                },
+            }
+        }
+
+        /// A sensible META for servers to send with this status when they have
+        /// nothing more specific to say.
+        pub fn default_meta(&self) -> &'static str {
+            match self {
+                Self::Input(_) => "Input required",
+                Self::Success => "text/gemini",
+                Self::Redirect(_) => "/",
+                Self::TemporaryFailure(x) => match x {
+                    TemporaryFailure::TemporaryFailure => "Temporary failure",
+                    TemporaryFailure::ServerUnavailable => "Server unavailable",
+                    TemporaryFailure::CgiError => "CGI error",
+                    TemporaryFailure::ProxyError => "Proxy error",
+                    TemporaryFailure::SlowDown => "Slow down",
+                },
+                Self::PermanentFailure(x) => match x {
+                    PermanentFailure::PermanentFailure => "Permanent failure",
+                    PermanentFailure::NotFound => "Not found",
+                    PermanentFailure::Gone => "Gone",
+                    PermanentFailure::ProxyRequestRefused => "Proxy request refused",
+                    PermanentFailure::BadRequest => "Bad request",
+                },
+                Self::ClientCertificateRequired(x) => match x {
+                    ClientCertificateRequired::ClientCertificateRequired => {
+                        "Client certificate required"
+                    }
+                    ClientCertificateRequired::CertificateNotAuthorized => {
+                        "Certificate not authorized"
+                    }
+                    ClientCertificateRequired::CertificateNotValid => {
+                        "Certificate not valid"
+                    }
+                },
+            }
+        }
+    }
+
     #[derive(Debug, Default, Clone, Copy, PartialEq)]
     pub enum Input {
         #[default]
         Input,
+        /// The input is sensitive, e.g. a password, and shouldn't be echoed: the
+        /// prompt should mask what the user types.
         Sensitive,
     }
 
@@ -347,533 +1130,5517 @@ pub mod status {
 
 pub use gemtext::{Gemtext, GemtextToken};
 pub mod gemtext {
+    use crate::uri::{Uri, UriOwned};
+    use std::ops::Range;
     use std::str::Lines;
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub enum GemtextToken<'a> {
-        Text(&'a str, TokenPreformatted<'a>),
+        Text(&'a str),
+        /// A preformatted block, the lines between a pair of ```` ``` ````
+        /// fences, where `0` is the block's text and `1` is the optional alt
+        /// text following the opening fence. The alt text is trimmed at both
+        /// ends, since it's often used as a language identifier, and is `None`
+        /// if that leaves it empty.
+        ///
+        /// The text is the block's lines exactly as they appear in the source,
+        /// line endings included (except for the last one). A block that's
+        /// never closed runs to the end of the document, so an opening fence on
+        /// the last line is an empty block rather than text.
+        Preformatted(&'a str, Option<&'a str>),
         /// A link line, where `0` is the the url and `1` is the optional
         /// dipslay name.
         ///
         /// ```not_rust
         /// =>[<whitespace>]<URL>[<whitespace><USER-FRIENDLY LINK NAME>]
         /// ```
+        ///
+        /// The whitespace separating the URL from the name may be any run of
+        /// spaces and tabs, which is consumed as a whole. Everything after it
+        /// is the name verbatim, including any whitespace within or after it.
+        /// A line with nothing after the separator has no name.
         Link(&'a str, Option<&'a str>),
         /// A heading line. Any line starting with one to three `#` characters.
         /// `0` is the heading text and `1` is the level (or `#` count).
         Heading(&'a str, u8),
-        /// A list item. Any line starting with a `*` is a list item.
-        /// '0' is the text and '1' is the indentation level
+        /// A list item. Any line starting with `* ` is a list item.
+        /// `0` is the text after the `* ` and `1` is the indentation level, the
+        /// number of whitespace bytes before the `*`.
         List(&'a str, u8),
         /// A quote line. Any line starting with a `>` is a quote line.
+        /// `0` is the text after the `>` and one optional space.
         Quote(&'a str),
     }
 
-    #[derive(Debug, Clone, Copy, Default, PartialEq)]
-    pub struct TokenPreformatted<'a> {
-        pub preformatted: bool,
-        pub alt_text: Option<&'a str>,
+    /// An owned version of [`GemtextToken`].
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum GemtextTokenOwned {
+        Text(String),
+        Preformatted(String, Option<String>),
+        Link(String, Option<String>),
+        Heading(String, u8),
+        List(String, u8),
+        Quote(String),
     }
 
-    #[derive(Debug, Clone)]
-    pub struct Gemtext<'a> {
-        lines: Lines<'a>,
-        pre: TokenPreformatted<'a>,
+    impl From<GemtextToken<'_>> for GemtextTokenOwned {
+        fn from(token: GemtextToken) -> Self {
+            match token {
+                GemtextToken::Text(text) => Self::Text(text.to_string()),
+                GemtextToken::Preformatted(text, alt) => {
+                    Self::Preformatted(text.to_string(), alt.map(String::from))
+                }
+                GemtextToken::Link(url, name) => {
+                    Self::Link(url.to_string(), name.map(String::from))
+                }
+                GemtextToken::Heading(text, level) => {
+                    Self::Heading(text.to_string(), level)
+                }
+                GemtextToken::List(text, level) => Self::List(text.to_string(), level),
+                GemtextToken::Quote(text) => Self::Quote(text.to_string()),
+            }
+        }
     }
 
-    impl<'a> Gemtext<'a> {
-        pub fn new(src: &'a str) -> Self {
-            Self {
-                lines: src.lines(),
-                pre: TokenPreformatted::default(),
+    impl GemtextTokenOwned {
+        pub fn as_ref(&self) -> GemtextToken<'_> {
+            match self {
+                Self::Text(text) => GemtextToken::Text(text),
+                Self::Preformatted(text, alt) => {
+                    GemtextToken::Preformatted(text, alt.as_deref())
+                }
+                Self::Link(url, name) => GemtextToken::Link(url, name.as_deref()),
+                Self::Heading(text, level) => GemtextToken::Heading(text, *level),
+                Self::List(text, level) => GemtextToken::List(text, *level),
+                Self::Quote(text) => GemtextToken::Quote(text),
             }
         }
     }
 
-    impl<'a> Iterator for Gemtext<'a> {
-        type Item = GemtextToken<'a>;
+    /// A parsed gemtext document which owns its tokens, so unlike [`Gemtext`]
+    /// it doesn't borrow the source and can be parsed once then kept around or
+    /// sent between threads.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Document {
+        tokens: Vec<GemtextTokenOwned>,
+    }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            let mut line = self.lines.next()?;
-
-            if line.starts_with("```") {
-                self.pre.preformatted = !self.pre.preformatted;
-                if self.pre.preformatted {
-                    self.pre.alt_text =
-                        Some(line.strip_prefix("```").unwrap().trim_start());
-                }
-                line = match self.lines.next() {
-                    Some(x) => x,
-                    None => {
-                        return Some(GemtextToken::Text(
-                            line,
-                            TokenPreformatted::default(),
-                        ));
-                    }
-                };
+    impl std::str::FromStr for Document {
+        type Err = std::convert::Infallible;
+
+        /// The same as [`Document::new`], for use with [`str::parse`].
+        fn from_str(src: &str) -> Result<Self, Self::Err> {
+            Ok(Self::new(src))
+        }
+    }
+
+    impl Document {
+        pub fn new(src: &str) -> Self {
+            Self {
+                tokens: Gemtext::new(src).map(GemtextTokenOwned::from).collect(),
             }
-            if !self.pre.preformatted {
-                if line.starts_with('#') {
-                    let count = line.chars().filter(|x| *x == '#').count();
-                    if count < 4 {
-                        let line = line
-                            .trim_start_matches(|x: char| x == '#' || x.is_whitespace());
-                        return Some(GemtextToken::Heading(line, count as u8));
-                    }
-                } else if let Some(line) = line.strip_prefix("=>") {
-                    if line.starts_with(char::is_whitespace) {
-                        let line = line.trim_start();
-                        let (bruh, moment) = line
-                            .split_once(char::is_whitespace)
-                            .map(|(x, y)| (x, Some(y.trim_start())))
-                            .unwrap_or((line, None));
-                        return Some(GemtextToken::Link(bruh, moment));
+        }
+
+        pub fn tokens(&self) -> &[GemtextTokenOwned] {
+            &self.tokens
+        }
+
+        /// Iterates over the document's tokens as borrowed [`GemtextToken`]s.
+        pub fn iter(&self) -> impl Iterator<Item = GemtextToken<'_>> {
+            self.tokens.iter().map(GemtextTokenOwned::as_ref)
+        }
+
+        /// Collects runs of consecutive link lines, which browsers often show
+        /// as a single menu, with each link resolved against `base`.
+        pub fn link_groups(&self, base: &Uri) -> Vec<Vec<(UriOwned, Option<String>)>> {
+            let mut groups = Vec::new();
+            let mut group = Vec::new();
+            for token in &self.tokens {
+                if let GemtextTokenOwned::Link(url, name) = token {
+                    if let Ok(url) = Uri::new(url) {
+                        group.push((url.resolve(base), name.clone()));
                     }
-                } else if line.trim_start().starts_with('*') {
-                    let trimmed = line.trim_start();
-                    let indentation = trimmed.len() - line.len();
-                    return Some(GemtextToken::List(
-                        trimmed.strip_prefix('*').unwrap(),
-                        indentation as u8,
-                    ));
-                } else if let Some(line) = line.strip_prefix('>') {
-                    return Some(GemtextToken::Quote(line.trim_start()));
+                } else if !group.is_empty() {
+                    groups.push(std::mem::take(&mut group));
+                }
+            }
+            if !group.is_empty() {
+                groups.push(group);
+            }
+            groups
+        }
+
+        /// Nests the document's headings by level, e.g. for a collapsible
+        /// sidebar.
+        ///
+        /// Each heading goes under the closest preceding heading of a lower
+        /// level, so a level 3 heading straight after a level 1 heading is
+        /// attached to the level 1 heading.
+        pub fn outline(&self) -> Outline {
+            fn close(stack: &mut Vec<OutlineNode>, roots: &mut Vec<OutlineNode>) {
+                let node = stack.pop().expect("stack isn't empty");
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => roots.push(node),
                 }
             }
 
-            Some(GemtextToken::Text(line, self.pre))
+            let mut roots = Vec::new();
+            let mut stack: Vec<OutlineNode> = Vec::new();
+            for token in &self.tokens {
+                let GemtextTokenOwned::Heading(title, level) = token else {
+                    continue;
+                };
+                while stack.last().is_some_and(|node| node.level >= *level) {
+                    close(&mut stack, &mut roots);
+                }
+                stack.push(OutlineNode {
+                    title: title.clone(),
+                    level: *level,
+                    children: Vec::new(),
+                });
+            }
+            while !stack.is_empty() {
+                close(&mut stack, &mut roots);
+            }
+            Outline { headings: roots }
         }
     }
-}
-pub mod uri {
-    #[derive(Debug, thiserror::Error)]
-    pub enum Error {
-        #[error("URI failed to validate")]
-        Invalid,
+
+    /// The tree of headings in a [`Document`], see [`Document::outline`].
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Outline {
+        /// The top-level headings, in document order.
+        pub headings: Vec<OutlineNode>,
     }
 
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-    pub struct Uri<'a> {
-        pub scheme: Option<&'a str>,
-        pub userinfo: Option<&'a str>,
-        pub host: Option<&'a str>,
-        pub port: Option<&'a str>,
-        pub path: Option<&'a str>,
-        pub query: Option<&'a str>,
-        pub fragment: Option<&'a str>,
+    /// A heading in an [`Outline`] along with the headings nested under it.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct OutlineNode {
+        pub title: String,
+        pub level: u8,
+        pub children: Vec<OutlineNode>,
     }
 
-    impl<'a> Uri<'a> {
-        pub fn new(mut src: &'a str) -> Result<Self, Error> {
-            let mut uri = Uri {
-                scheme: None,
-                userinfo: None,
-                host: None,
-                port: None,
-                path: None,
-                query: None,
-                fragment: None,
-            };
+    /// An iterator over the tokens of a gemtext document.
+    ///
+    /// Tokens keep any trailing whitespace from their line, as [`str::lines`]
+    /// only strips the line ending. Renderers that don't want it can opt into
+    /// [`Gemtext::trim_trailing_whitespace`].
+    #[derive(Debug, Clone)]
+    pub struct Gemtext<'a> {
+        src: &'a str,
+        lines: Lines<'a>,
+        max_heading_level: u8,
+        trim_trailing_whitespace: bool,
+        /// Where the last token came from in `src`, see [`Gemtext::spanned`].
+        span: Range<usize>,
+    }
 
-            if let Some((rest, frag)) = src.split_once('#') {
-                src = rest;
-                uri.fragment = Some(frag);
-            }
-            if let Some((rest, query)) = src.split_once('?') {
-                src = rest;
-                uri.query = Some(query);
+    impl<'a> Gemtext<'a> {
+        pub fn new(src: &'a str) -> Self {
+            Self::with_max_heading_level(src, 3)
+        }
+
+        /// Like [`Gemtext::new`], but lines with up to `max` `#` characters are
+        /// headings rather than the usual three. Anything deeper is still text.
+        pub fn with_max_heading_level(src: &'a str, max: u8) -> Self {
+            Self {
+                src,
+                lines: src.lines(),
+                max_heading_level: max,
+                trim_trailing_whitespace: false,
+                span: 0..0,
             }
+        }
 
-            if src.starts_with(char::is_alphabetic) {
-                if let Some((scheme, rest)) = src.split_once(':') {
-                    if scheme.chars().all(is_scheme) {
-                        uri.scheme = Some(scheme);
-                        src = rest;
-                    }
-                }
+        /// Strips trailing whitespace from text, heading, list and quote lines.
+        /// Preformatted blocks are always left untouched.
+        pub fn trim_trailing_whitespace(mut self, trim: bool) -> Self {
+            self.trim_trailing_whitespace = trim;
+            self
+        }
+
+        /// Yields each token along with the byte range of `src` it was parsed
+        /// from, e.g. for an editor to map rendered elements back to the
+        /// source.
+        ///
+        /// The range covers the token's line without its line ending, or for a
+        /// preformatted block everything from the opening fence to the end of
+        /// the closing one.
+        pub fn spanned(self) -> Spanned<'a> {
+            Spanned(self)
+        }
+
+        /// Yields only the links, with each target resolved against `base`,
+        /// e.g. the URL of the page being rendered. Links whose target isn't a
+        /// valid URI are skipped.
+        pub fn links_resolved<'b>(self, base: &Uri<'b>) -> LinksResolved<'a, 'b> {
+            LinksResolved {
+                tokens: self,
+                base: *base,
             }
+        }
 
-            if let Some(rest) = src.strip_prefix("//") {
-                src = rest;
-                if let Some((rest, path)) = rest.split_once('/') {
-                    uri.path = Some(path);
-                    src = rest;
-                }
+        /// The offset of `line`, which is a slice of `src`, into `src`.
+        fn offset(&self, line: &str) -> usize {
+            line.as_ptr() as usize - self.src.as_ptr() as usize
+        }
+    }
+
+    /// An iterator over the tokens of a gemtext document and where they are in
+    /// the source, see [`Gemtext::spanned`].
+    #[derive(Debug, Clone)]
+    pub struct Spanned<'a>(Gemtext<'a>);
+
+    impl<'a> Iterator for Spanned<'a> {
+        type Item = (GemtextToken<'a>, Range<usize>);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let token = self.0.next()?;
+            Some((token, self.0.span.clone()))
+        }
+    }
+
+    /// An iterator over the links of a gemtext document resolved to absolute
+    /// URLs, see [`Gemtext::links_resolved`].
+    #[derive(Debug, Clone)]
+    pub struct LinksResolved<'a, 'b> {
+        tokens: Gemtext<'a>,
+        base: Uri<'b>,
+    }
+
+    impl<'a> Iterator for LinksResolved<'a, '_> {
+        type Item = (UriOwned, Option<&'a str>);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.tokens.find_map(|token| match token {
+                GemtextToken::Link(url, name) => Uri::new(url)
+                    .ok()
+                    .map(|url| (url.resolve(&self.base), name)),
+                _ => None,
+            })
+        }
+    }
+
+    impl<'a> Iterator for Gemtext<'a> {
+        type Item = GemtextToken<'a>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let line = self.lines.next()?;
+            let start = self.offset(line);
+            self.span = start..start + line.len();
 
-                if let Some((rest, port)) = src.rsplit_once(':') {
-                    if port.chars().all(|x| x.is_ascii_digit()) {
-                        uri.port = Some(port);
-                        src = rest;
+            if let Some(alt) = line.strip_prefix("```") {
+                let alt = alt.trim();
+                // Lines are slices of `src`, so the block is everything from the
+                // start of its first line to the end of its last.
+                let mut block = None;
+                while let Some(line) = self.lines.next() {
+                    let offset = self.offset(line);
+                    self.span.end = offset + line.len();
+                    if line.starts_with("```") {
+                        break;
                     }
+                    let start = block.map_or(offset, |(start, _)| start);
+                    block = Some((start, offset + line.len()));
                 }
-                if let Some((userinfo, host)) = src.split_once('@') {
-                    uri.userinfo = Some(userinfo);
-                    uri.host = Some(host);
-                } else {
-                    uri.host = Some(src);
-                }
-            } else {
-                uri.path = Some(src);
+                let text = block.map_or("", |(start, end)| &self.src[start..end]);
+                return Some(GemtextToken::Preformatted(
+                    text,
+                    (!alt.is_empty()).then_some(alt),
+                ));
             }
 
-            Ok(uri)
+            Some(parse_line(
+                line,
+                self.max_heading_level,
+                self.trim_trailing_whitespace,
+            ))
         }
     }
 
-    impl<'a> From<&'a UriOwned> for Uri<'a> {
-        fn from(uri: &'a UriOwned) -> Self {
-            Self {
-                scheme: uri.scheme.as_deref(),
-                userinfo: uri.userinfo.as_deref(),
-                host: uri.host.as_deref(),
-                port: uri.port.as_deref(),
-                path: uri.path.as_deref(),
-                query: uri.query.as_deref(),
-                fragment: uri.fragment.as_deref(),
+    /// Parses a line outside of a preformatted block.
+    fn parse_line(mut line: &str, max_heading_level: u8, trim: bool) -> GemtextToken<'_> {
+        if trim {
+            line = line.trim_end();
+        }
+        if line.starts_with('#') {
+            // Only the leading run counts, a `#` later on is part of the text.
+            let text = line.trim_start_matches('#');
+            let count = line.len() - text.len();
+            if count <= usize::from(max_heading_level) {
+                return GemtextToken::Heading(text.trim_start(), count as u8);
             }
+        } else if let Some(line) = line.strip_prefix("=>") {
+            // A link line without a URL is just text.
+            if line.starts_with(char::is_whitespace) && !line.trim_start().is_empty() {
+                let line = line.trim_start();
+                let (bruh, moment) = line
+                    .split_once(char::is_whitespace)
+                    .map(|(x, y)| (x, Some(y.trim_start()).filter(|y| !y.is_empty())))
+                    .unwrap_or((line, None));
+                return GemtextToken::Link(bruh, moment);
+            }
+        } else if let Some(item) = line.trim_start().strip_prefix("* ") {
+            let indentation = line.len() - line.trim_start().len();
+            return GemtextToken::List(
+                item,
+                u8::try_from(indentation).unwrap_or(u8::MAX),
+            );
+        } else if let Some(line) = line.strip_prefix('>') {
+            return GemtextToken::Quote(line.strip_prefix(' ').unwrap_or(line));
         }
+
+        GemtextToken::Text(line)
     }
 
-    impl std::fmt::Display for Uri<'_> {
-        fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-            if let Some(scheme) = self.scheme {
-                write!(f, "{scheme}")?;
-                write!(f, ":")?;
+    /// Parses gemtext incrementally off a reader, e.g. a body from
+    /// [`Response::read_streaming`](crate::Response::read_streaming), so a
+    /// long page can be rendered as it arrives.
+    ///
+    /// Tokens are the same as [`Gemtext`]'s, only owned. A preformatted block
+    /// is yielded once its closing fence (or the end of input) has been read.
+    /// Invalid UTF-8 is replaced with U+FFFD.
+    #[derive(Debug)]
+    pub struct GemtextReader<R> {
+        reader: R,
+        max_heading_level: u8,
+        trim_trailing_whitespace: bool,
+    }
+
+    impl<R: std::io::BufRead> GemtextReader<R> {
+        pub fn new(reader: R) -> Self {
+            Self::with_max_heading_level(reader, 3)
+        }
+
+        /// See [`Gemtext::with_max_heading_level`].
+        pub fn with_max_heading_level(reader: R, max: u8) -> Self {
+            Self {
+                reader,
+                max_heading_level: max,
+                trim_trailing_whitespace: false,
             }
+        }
 
-            if self.host.is_some() {
-                write!(f, "//")?;
-                if let Some(userinfo) = self.userinfo {
-                    write!(f, "{userinfo}")?;
-                    write!(f, "@")?;
-                }
-                if let Some(host) = self.host {
-                    write!(f, "{host}")?;
+        /// See [`Gemtext::trim_trailing_whitespace`].
+        pub fn trim_trailing_whitespace(mut self, trim: bool) -> Self {
+            self.trim_trailing_whitespace = trim;
+            self
+        }
+
+        pub fn into_inner(self) -> R {
+            self.reader
+        }
+
+        /// Reads the next line along with its line ending, which is empty at
+        /// the end of input.
+        fn read_line(&mut self) -> std::io::Result<Option<(String, String)>> {
+            let mut buf = Vec::new();
+            if self.reader.read_until(b'\n', &mut buf)? == 0 {
+                return Ok(None);
+            }
+            let mut line = String::from_utf8_lossy(&buf).into_owned();
+            // The same line endings as `str::lines`.
+            let mut ending = String::new();
+            if line.ends_with('\n') {
+                line.pop();
+                ending.insert(0, '\n');
+                if line.ends_with('\r') {
+                    line.pop();
+                    ending.insert(0, '\r');
                 }
-                if let Some(port) = self.port {
-                    write!(f, ":")?;
-                    write!(f, "{port}")?;
+            }
+            Ok(Some((line, ending)))
+        }
+
+        fn read_token(&mut self) -> std::io::Result<Option<GemtextTokenOwned>> {
+            let Some((line, _)) = self.read_line()? else {
+                return Ok(None);
+            };
+            if let Some(alt) = line.strip_prefix("```") {
+                let alt = alt.trim();
+                // Like `Gemtext`, keep the line endings between the block's lines
+                // but not after the last one.
+                let mut text = String::new();
+                let mut ending = String::new();
+                while let Some((line, next_ending)) = self.read_line()? {
+                    if line.starts_with("```") {
+                        break;
+                    }
+                    text.push_str(&ending);
+                    text.push_str(&line);
+                    ending = next_ending;
                 }
-                if let Some(path) = self.path {
-                    write!(f, "/")?;
-                    write!(f, "{}", path.trim_start_matches("/"))?;
+                return Ok(Some(GemtextTokenOwned::Preformatted(
+                    text,
+                    (!alt.is_empty()).then(|| alt.to_string()),
+                )));
+            }
+            Ok(Some(
+                parse_line(&line, self.max_heading_level, self.trim_trailing_whitespace)
+                    .into(),
+            ))
+        }
+    }
+
+    impl<R: std::io::BufRead> Iterator for GemtextReader<R> {
+        type Item = std::io::Result<GemtextTokenOwned>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.read_token().transpose()
+        }
+    }
+
+    /// Writes gemtext line by line, the inverse of [`Gemtext`], e.g. for a
+    /// server generating pages.
+    ///
+    /// Parsing the result with [`Gemtext::new`] gives back the same tokens,
+    /// with a few exceptions where gemtext has no way to say what was asked
+    /// for:
+    ///
+    /// - Line breaks in headings, link labels, list items, quotes and alt text
+    ///   become spaces. Text is split into a line per line break instead.
+    /// - A text line or a line of a preformatted block that would otherwise be
+    ///   read as markup, such as `* not a list`, gets a zero width space
+    ///   (U+200B) put in front of it.
+    /// - Whitespace in link URLs is percent-encoded.
+    /// - Whitespace at the start of headings and link labels is lost.
+    #[derive(Debug, Clone, Default)]
+    pub struct GemtextBuilder {
+        out: String,
+    }
+
+    impl GemtextBuilder {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// A heading of `level` `#`s. Anything deeper than `3` is only a
+        /// heading to [`Gemtext::with_max_heading_level`].
+        pub fn heading(self, level: u8, text: &str) -> Self {
+            let prefix = "#".repeat(usize::from(level.max(1)));
+            self.line(&format!("{prefix} {}", one_line(text)))
+        }
+
+        pub fn link(self, url: &str, label: Option<&str>) -> Self {
+            let mut line = String::from("=> ");
+            for c in url.chars() {
+                if c.is_whitespace() {
+                    for byte in c.to_string().bytes() {
+                        line.push_str(&format!("%{byte:02X}"));
+                    }
+                } else {
+                    line.push(c);
                 }
-            } else if let Some(path) = self.path {
-                write!(f, "{path}")?;
             }
-            if let Some(query) = self.query {
-                write!(f, "?")?;
-                write!(f, "{query}")?;
+            if let Some(label) = label {
+                line.push(' ');
+                line.push_str(&one_line(label));
             }
-            if let Some(fragment) = self.fragment {
-                write!(f, "#")?;
-                write!(f, "{fragment}")?;
+            self.line(&line)
+        }
+
+        /// A list item, written as `* ` followed by `text`, which is what
+        /// [`GemtextToken::List`] holds after parsing.
+        pub fn list_item(self, text: &str) -> Self {
+            self.line(&format!("* {}", one_line(text)))
+        }
+
+        pub fn quote(self, text: &str) -> Self {
+            self.line(&format!("> {}", one_line(text)))
+        }
+
+        /// A preformatted block, with `alt` after the opening fence.
+        pub fn preformatted(mut self, text: &str, alt: Option<&str>) -> Self {
+            self = self.line(&format!("```{}", alt.map(one_line).unwrap_or_default()));
+            for line in text.split('\n') {
+                self = if line.starts_with("```") {
+                    self.line(&format!("\u{200B}{line}"))
+                } else {
+                    self.line(line)
+                };
             }
-            Ok(())
+            self.line("```")
         }
-    }
 
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    pub struct UriOwned {
-        pub scheme: Option<String>,
-        pub userinfo: Option<String>,
-        pub host: Option<String>,
-        pub port: Option<String>,
-        pub path: Option<String>,
-        pub query: Option<String>,
-        pub fragment: Option<String>,
-    }
+        /// Plain text, one text line for every line in `text`.
+        pub fn text(mut self, text: &str) -> Self {
+            for line in text.split('\n') {
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                self = match Gemtext::new(line).next() {
+                    Some(GemtextToken::Text(_)) | None => self.line(line),
+                    Some(_) => self.line(&format!("\u{200B}{line}")),
+                };
+            }
+            self
+        }
 
-    impl From<Uri<'_>> for UriOwned {
-        fn from(uri: Uri) -> Self {
-            Self {
-                scheme: uri.scheme.map(String::from),
-                userinfo: uri.userinfo.map(String::from),
-                host: uri.host.map(String::from),
-                port: uri.port.map(String::from),
-                path: uri.path.map(String::from),
-                query: uri.query.map(String::from),
-                fragment: uri.fragment.map(String::from),
+        /// Writes a parsed token back out, e.g. after filtering a document's
+        /// tokens. An indented list item keeps its indentation.
+        pub fn token(self, token: GemtextToken) -> Self {
+            match token {
+                GemtextToken::Text(text) => self.text(text),
+                GemtextToken::Preformatted(text, alt) => self.preformatted(text, alt),
+                GemtextToken::Link(url, name) => self.link(url, name),
+                GemtextToken::Heading(text, level) => self.heading(level, text),
+                GemtextToken::List(text, 0) => self.list_item(text),
+                GemtextToken::List(text, indentation) => self.line(&format!(
+                    "{}* {}",
+                    " ".repeat(usize::from(indentation)),
+                    one_line(text)
+                )),
+                GemtextToken::Quote(text) => self.quote(text),
             }
         }
-    }
 
-    impl UriOwned {
-        pub fn as_ref(&self) -> Uri {
-            self.into()
+        /// Returns the document written so far.
+        pub fn build(self) -> String {
+            self.out
         }
-    }
 
-    impl std::fmt::Display for UriOwned {
-        fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-            let uri: Uri = self.into();
-            write!(f, "{uri}")
+        fn line(mut self, line: &str) -> Self {
+            self.out.push_str(line);
+            self.out.push('\n');
+            self
         }
     }
 
-    fn is_scheme(c: char) -> bool {
-        c.is_alphabetic() || c.is_ascii_digit() || "+-.".contains(c)
+    fn one_line(text: &str) -> String {
+        text.replace(['\r', '\n'], " ")
     }
 
-    pub fn percent_decode(s: impl AsRef<str>) -> Option<String> {
-        let s = s.as_ref();
-        let mut out = String::new();
-        let mut rem = 0;
-        for (i, ch) in s.chars().enumerate() {
-            if rem == 0 {
-                if ch == '%' {
-                    rem = 2;
-                } else {
-                    out.push(ch);
-                }
+    /// A piece of a text line as split up by [`linkify`].
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum TextSpan<'a> {
+        Text(&'a str),
+        /// A bare URL such as `gemini://example.com/`.
+        Url(&'a str),
+    }
+
+    /// Splits a text line into plain text and the bare URLs (`gemini://`,
+    /// `https://`, etc.) appearing in it, so a renderer can make them clickable.
+    ///
+    /// A URL runs until the next whitespace, minus any trailing punctuation.
+    /// This knows nothing about preformatted blocks, only pass it lines that
+    /// aren't preformatted.
+    pub fn linkify(line: &str) -> Vec<TextSpan<'_>> {
+        let is_scheme = |c: char| c.is_ascii_alphanumeric() || "+-.".contains(c);
+        let mut spans = Vec::new();
+        let mut start = 0;
+        let mut search = 0;
+        while let Some(pos) = line[search..].find("://") {
+            let sep = search + pos;
+            search = sep + 3;
+            let scheme_start = line[start..sep]
+                .char_indices()
+                .rev()
+                .take_while(|(_, c)| is_scheme(*c))
+                .last()
+                .map_or(sep, |(i, _)| start + i);
+            if !line[scheme_start..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                continue;
+            }
+            let end = line[sep..]
+                .find(char::is_whitespace)
+                .map_or(line.len(), |i| sep + i);
+            let url = line[scheme_start..end]
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+            if scheme_start + url.len() <= sep + 3 {
                 continue;
             }
-            rem -= 1;
-            if rem == 0 {
-                out.push(u8::from_str_radix(&s[i - 1..=i], 16).ok().map(char::from)?);
+            if scheme_start > start {
+                spans.push(TextSpan::Text(&line[start..scheme_start]));
+            }
+            spans.push(TextSpan::Url(url));
+            start = scheme_start + url.len();
+            search = start;
+        }
+        if start < line.len() {
+            spans.push(TextSpan::Text(&line[start..]));
+        }
+        spans
+    }
+
+    /// Whether a document has nothing to show, i.e. every line is blank or a
+    /// preformatted fence. Browsers can use this to show an "empty page" notice.
+    pub fn is_empty(src: &str) -> bool {
+        src.lines()
+            .all(|line| line.trim().is_empty() || line.starts_with("```"))
+    }
+
+    /// The text a reader would see in `src`, for indexing it for search.
+    ///
+    /// Text, heading, list and quote lines are kept without their markers,
+    /// and links contribute their names but not their URLs. Preformatted
+    /// blocks are left out entirely. All whitespace, line breaks included,
+    /// is collapsed into single spaces.
+    pub fn index_text(src: &str) -> String {
+        let mut words = Vec::new();
+        for token in Gemtext::new(src) {
+            let text = match token {
+                GemtextToken::Text(text)
+                | GemtextToken::Heading(text, _)
+                | GemtextToken::List(text, _)
+                | GemtextToken::Quote(text)
+                | GemtextToken::Link(_, Some(text)) => text,
+                GemtextToken::Link(_, None) | GemtextToken::Preformatted(..) => continue,
+            };
+            words.extend(text.split_whitespace());
+        }
+        words.join(" ")
+    }
+
+    /// Replaces the URL of every link line in `src` with `f(url)`, e.g. for a
+    /// mirror pointing absolute links at its local copies. Everything else,
+    /// link names and line endings included, is kept byte for byte.
+    ///
+    /// The new URLs are written as is, so they mustn't contain whitespace.
+    pub fn rewrite_links(src: &str, mut f: impl FnMut(&str) -> String) -> String {
+        let mut out = String::with_capacity(src.len());
+        let mut copied = 0;
+        for token in Gemtext::new(src) {
+            if let GemtextToken::Link(url, _) = token {
+                // Tokens are slices of `src`.
+                let start = url.as_ptr() as usize - src.as_ptr() as usize;
+                out.push_str(&src[copied..start]);
+                out.push_str(&f(url));
+                copied = start + url.len();
             }
         }
-        Some(out)
+        out.push_str(&src[copied..]);
+        out
     }
 
-    // TODO: Percent Encode
+    /// Collects every preformatted block in `src` along with its alt text.
+    ///
+    /// The alt text is `None` when the opening fence has nothing after it, and
+    /// the lines of each block are joined with `\n`. A block left open at the
+    /// end of the document runs to the last line.
+    pub fn code_blocks(src: &str) -> Vec<(Option<&str>, String)> {
+        Gemtext::new(src)
+            .filter_map(|token| match token {
+                GemtextToken::Preformatted(text, alt) => {
+                    Some((alt, text.lines().collect::<Vec<_>>().join("\n")))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
     #[cfg(test)]
     mod tests {
         use super::*;
 
         #[test]
-        fn percent() {
+        fn linkify_bare_urls() {
             assert_eq!(
-            percent_decode("%21%40%23%24%25%2A%28%29With Some Text in the middle%7E%7B%7D%3A%3C%3E%3F_%2B").unwrap(),
-            "!@#$%*()With Some Text in the middle~{}:<>?_+");
+                linkify("See gemini://example.com/page.gmi, or https://example.org."),
+                vec![
+                    TextSpan::Text("See "),
+                    TextSpan::Url("gemini://example.com/page.gmi"),
+                    TextSpan::Text(", or "),
+                    TextSpan::Url("https://example.org"),
+                    TextSpan::Text("."),
+                ]
+            );
+            assert_eq!(
+                linkify("nothing :// here"),
+                vec![TextSpan::Text("nothing :// here")]
+            );
         }
 
         #[test]
-        fn uri() {
-            let test1 = "ftp://ftp.is.co.za/rfc/rfc1808.txt";
-            let test2 = "http://www.ietf.org/rfc/rfc2396.txt";
-            let test3 = "ldap://[2001:db8::7]/c=GB?objectClass?one";
-            let test4 = "mailto:John.Doe@example.com";
-            let test5 = "news:comp.infosystems.www.servers.unix";
-            let test6 = "tel:+1-816-555-1212";
-            let test7 = "telnet://192.0.2.16:80/";
-            let test8 = "urn:oasis:names:specification:docbook:dtd:xml:4.1.2";
-            let test9 = "https://datatracker.ietf.org/doc/html/rfc3986#section-1.1.2";
-            let test10 = "https://www.youtube.com/watch?v=QyjyWUrHsFc";
-            let test11 =
-                "https://john.doe@www.example.com:1234/forum/questions/?query#Frag";
-            Uri::new(test1).unwrap();
-            Uri::new(test2).unwrap();
-            Uri::new(dbg!(test3)).unwrap();
-            Uri::new(test4).unwrap();
-            Uri::new(test5).unwrap();
-            Uri::new(test6).unwrap();
-            Uri::new(test7).unwrap();
-            let uri2 = Uri::new(test8).unwrap();
+        fn builder_round_trip() {
+            let src = GemtextBuilder::new()
+                .heading(1, "Title")
+                .text("Some text\nover two lines")
+                .link("/a b", Some("A"))
+                .link("gemini://example.com/", None)
+                .list_item("item")
+                .quote("quoted")
+                .preformatted("fn main() {\n    ```\n}", Some("rust"))
+                .preformatted("", None)
+                .heading(2, "Sub\nheading")
+                .text("* not a list\n=> not a link")
+                .text("")
+                .build();
             assert_eq!(
-                uri2.path,
-                Some("oasis:names:specification:docbook:dtd:xml:4.1.2")
+                Gemtext::new(&src).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Heading("Title", 1),
+                    GemtextToken::Text("Some text"),
+                    GemtextToken::Text("over two lines"),
+                    GemtextToken::Link("/a%20b", Some("A")),
+                    GemtextToken::Link("gemini://example.com/", None),
+                    GemtextToken::List("item", 0),
+                    GemtextToken::Quote("quoted"),
+                    GemtextToken::Preformatted("fn main() {\n    ```\n}", Some("rust")),
+                    GemtextToken::Preformatted("", None),
+                    GemtextToken::Heading("Sub heading", 2),
+                    GemtextToken::Text("\u{200B}* not a list"),
+                    GemtextToken::Text("\u{200B}=> not a link"),
+                    GemtextToken::Text(""),
+                ]
             );
-            Uri::new(test9).unwrap();
-            Uri::new(test10).unwrap();
-            let uri = Uri::new(test11).unwrap();
-            assert_eq!(uri.scheme, Some("https"));
-            assert_eq!(uri.userinfo, Some("john.doe"));
-            assert_eq!(uri.host, Some("www.example.com"));
-            assert_eq!(uri.port, Some("1234"));
-            assert_eq!(uri.path, Some("forum/questions/"));
-            assert_eq!(uri.query, Some("query"));
-            assert_eq!(uri.fragment, Some("Frag"));
         }
 
         #[test]
-        fn uri_owned() {
-            let test1 = "https://www.youtube.com/watch?v=QyjyWUrHsFc";
-            let test2 = "http://www.ietf.org/rfc/rfc2396.txt";
-            let test3 = "ldap://[2001:db8::7]/c=GB?objectClass?one";
-            let test4 = "mailto:John.Doe@example.com";
-            let test5 = "news:comp.infosystems.www.servers.unix";
-            let test6 = "tel:+1-816-555-1212";
+        fn list_round_trip() {
+            let src = "* item\n*  spaced\n  * nested\n";
+            let tokens: Vec<_> = Gemtext::new(src).collect();
+            assert_eq!(
+                tokens,
+                [
+                    GemtextToken::List("item", 0),
+                    GemtextToken::List(" spaced", 0),
+                    GemtextToken::List("nested", 2),
+                ]
+            );
+            let written = tokens
+                .into_iter()
+                .fold(GemtextBuilder::new(), GemtextBuilder::token)
+                .build();
+            assert_eq!(written, src);
+        }
+
+        #[test]
+        fn document_outlives_source() {
+            let document: Document = {
+                let src = String::from("# Title\n=> /a A\n```\npre\n```\n");
+                src.parse().unwrap()
+            };
+            assert_eq!(
+                document.tokens(),
+                [
+                    GemtextTokenOwned::Heading("Title".to_string(), 1),
+                    GemtextTokenOwned::Link("/a".to_string(), Some("A".to_string())),
+                    GemtextTokenOwned::Preformatted("pre".to_string(), None),
+                ]
+            );
+        }
+
+        #[test]
+        fn document_link_groups() {
+            let document = Document::new(
+                "# Menu\n=> a.gmi A\n=> /b\n\n=> ../c.gmi C\n=> gemini://other.net/ Other\n",
+            );
+            let base = Uri::new("gemini://example.com/dir/page.gmi").unwrap();
+            let groups = document.link_groups(&base);
+            let groups: Vec<Vec<(String, Option<&str>)>> = groups
+                .iter()
+                .map(|group| {
+                    group
+                        .iter()
+                        .map(|(url, name)| (url.to_string(), name.as_deref()))
+                        .collect()
+                })
+                .collect();
+            assert_eq!(
+                groups,
+                [
+                    vec![
+                        ("gemini://example.com/dir/a.gmi".to_string(), Some("A")),
+                        ("gemini://example.com/b".to_string(), None),
+                    ],
+                    vec![
+                        ("gemini://example.com/c.gmi".to_string(), Some("C")),
+                        ("gemini://other.net/".to_string(), Some("Other")),
+                    ],
+                ]
+            );
+        }
+
+        #[test]
+        fn document_outline() {
+            let node = |title: &str, level, children| OutlineNode {
+                title: title.to_string(),
+                level,
+                children,
+            };
+            let document = Document::new(
+                "# A\n## A.1\ntext\n### A.1.a\n## A.2\n# B\n### B.?\n## B.1\n# C\n",
+            );
+            assert_eq!(
+                document.outline().headings,
+                [
+                    node(
+                        "A",
+                        1,
+                        vec![
+                            node("A.1", 2, vec![node("A.1.a", 3, vec![])]),
+                            node("A.2", 2, vec![]),
+                        ]
+                    ),
+                    node("B", 1, vec![node("B.?", 3, vec![]), node("B.1", 2, vec![])]),
+                    node("C", 1, vec![]),
+                ]
+            );
+            // A document that starts below level 1 still has those at the top.
+            assert_eq!(
+                Document::new("### deep\n# top\n").outline().headings,
+                [node("deep", 3, vec![]), node("top", 1, vec![])]
+            );
+            assert_eq!(Document::new("no headings").outline(), Outline::default());
+        }
+
+        #[test]
+        fn bare_headings() {
+            // A heading of nothing but `#`s is an empty heading, not text.
+            assert_eq!(
+                Gemtext::new("###\n#\n####\n#Title\n##\tTabbed").collect::<Vec<_>>(),
+                [
+                    GemtextToken::Heading("", 3),
+                    GemtextToken::Heading("", 1),
+                    GemtextToken::Text("####"),
+                    GemtextToken::Heading("Title", 1),
+                    GemtextToken::Heading("Tabbed", 2),
+                ]
+            );
+        }
+
+        #[test]
+        fn hashes_later_in_heading() {
+            assert_eq!(
+                Gemtext::new("## See C# docs\n# # nested\n### #1 ## rank\n")
+                    .collect::<Vec<_>>(),
+                [
+                    GemtextToken::Heading("See C# docs", 2),
+                    GemtextToken::Heading("# nested", 1),
+                    GemtextToken::Heading("#1 ## rank", 3),
+                ]
+            );
+        }
+
+        #[test]
+        fn max_heading_level() {
+            let src = "### Three\n#### Four\n";
+            assert_eq!(
+                Gemtext::new(src).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Heading("Three", 3),
+                    GemtextToken::Text("#### Four"),
+                ]
+            );
+            assert_eq!(
+                Gemtext::with_max_heading_level(src, 6).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Heading("Three", 3),
+                    GemtextToken::Heading("Four", 4),
+                ]
+            );
+        }
+
+        #[test]
+        fn trim_trailing_whitespace() {
+            let src = "text  \n# heading \t\n* item \n> quote  \n```\npre  \n```\nend";
+            assert_eq!(
+                Gemtext::new(src).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Text("text  "),
+                    GemtextToken::Heading("heading \t", 1),
+                    GemtextToken::List("item ", 0),
+                    GemtextToken::Quote("quote  "),
+                    GemtextToken::Preformatted("pre  ", None),
+                    GemtextToken::Text("end"),
+                ]
+            );
+            assert_eq!(
+                Gemtext::new(src)
+                    .trim_trailing_whitespace(true)
+                    .collect::<Vec<_>>(),
+                [
+                    GemtextToken::Text("text"),
+                    GemtextToken::Heading("heading", 1),
+                    GemtextToken::List("item", 0),
+                    GemtextToken::Quote("quote"),
+                    GemtextToken::Preformatted("pre  ", None),
+                    GemtextToken::Text("end"),
+                ]
+            );
+        }
+
+        #[test]
+        fn quote_lines() {
+            assert_eq!(
+                Gemtext::new(">quote\n> quote\n>   indented\n```\n> pre\n")
+                    .collect::<Vec<_>>(),
+                [
+                    GemtextToken::Quote("quote"),
+                    GemtextToken::Quote("quote"),
+                    GemtextToken::Quote("  indented"),
+                    GemtextToken::Preformatted("> pre", None),
+                ]
+            );
+        }
+
+        #[test]
+        fn list_items() {
+            assert_eq!(
+                Gemtext::new("* one\n  * nested\n*bold*\n").collect::<Vec<_>>(),
+                [
+                    GemtextToken::List("one", 0),
+                    GemtextToken::List("nested", 2),
+                    GemtextToken::Text("*bold*"),
+                ]
+            );
+        }
+
+        #[test]
+        fn link_without_url() {
+            assert_eq!(
+                Gemtext::new("=>   \n").collect::<Vec<_>>(),
+                [GemtextToken::Text("=>   ")]
+            );
+        }
+
+        #[test]
+        fn tab_separated_links() {
+            let mut tokens =
+                Gemtext::new("=>\tgemini://host/\t \tName\n=>\tgemini://host/\n");
+            assert_eq!(
+                tokens.next(),
+                Some(GemtextToken::Link("gemini://host/", Some("Name")))
+            );
+            assert_eq!(
+                tokens.next(),
+                Some(GemtextToken::Link("gemini://host/", None))
+            );
+        }
+
+        #[test]
+        fn link_name_separator() {
+            assert_eq!(
+                Gemtext::new("=> /a    Two  spaced\t name  \n=> /b   \n")
+                    .collect::<Vec<_>>(),
+                [
+                    GemtextToken::Link("/a", Some("Two  spaced\t name  ")),
+                    GemtextToken::Link("/b", None),
+                ]
+            );
+        }
+
+        #[test]
+        fn empty_documents() {
+            assert!(is_empty(""));
+            assert!(is_empty("\n  \n\t\r\n```\n\n```\n"));
+            assert!(!is_empty("\n\nHello\n"));
+        }
+
+        #[test]
+        fn preformatted_blocks() {
+            let src = "```rust\nfn main() {\r\n    # not a heading\n}\n```\n=> after\n```\n```\n```unclosed\n* a\n\n";
+            assert_eq!(
+                Gemtext::new(src).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Preformatted(
+                        "fn main() {\r\n    # not a heading\n}",
+                        Some("rust")
+                    ),
+                    GemtextToken::Link("after", None),
+                    GemtextToken::Preformatted("", None),
+                    GemtextToken::Preformatted("* a\n", Some("unclosed")),
+                ]
+            );
+        }
+
+        #[test]
+        fn links_resolved() {
+            let src = "=> /a A\n# Heading\n=> b\n=> gemini://other.org/";
+            let base = Uri::new("gemini://example.com/dir/page.gmi").unwrap();
+            let links: Vec<_> = Gemtext::new(src)
+                .links_resolved(&base)
+                .map(|(url, name)| (url.to_string(), name))
+                .collect();
+            assert_eq!(
+                links,
+                [
+                    ("gemini://example.com/a".to_string(), Some("A")),
+                    ("gemini://example.com/dir/b".to_string(), None),
+                    ("gemini://other.org/".to_string(), None),
+                ]
+            );
+        }
+
+        #[test]
+        fn reader_matches_gemtext() {
+            let src = "# Title  \r\n\n=> /a  A link\n* item\r\n> quote\n```rust \r\nfn a() {}\r\n\n  b\n```\n#### deep\n```\nunclosed\r\n";
+            for trim in [false, true] {
+                // A tiny buffer makes lines arrive over several reads.
+                let reader = std::io::BufReader::with_capacity(3, src.as_bytes());
+                let tokens: Vec<_> = GemtextReader::new(reader)
+                    .trim_trailing_whitespace(trim)
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                let expected: Vec<_> = Gemtext::new(src)
+                    .trim_trailing_whitespace(trim)
+                    .map(GemtextTokenOwned::from)
+                    .collect();
+                assert_eq!(tokens, expected);
+            }
+            let tokens: Vec<_> = GemtextReader::new(&b"a\xFFb"[..])
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(tokens, [GemtextTokenOwned::Text("a\u{FFFD}b".into())]);
+        }
+
+        #[test]
+        fn spans() {
+            let src = "# Title  \r\n\n```alt\ncode\n```\n=> /a\n```\nunclosed";
+            let spans: Vec<_> = Gemtext::new(src)
+                .trim_trailing_whitespace(true)
+                .spanned()
+                .map(|(token, span)| (token, &src[span]))
+                .collect();
+            assert_eq!(
+                spans,
+                [
+                    (GemtextToken::Heading("Title", 1), "# Title  "),
+                    (GemtextToken::Text(""), ""),
+                    (
+                        GemtextToken::Preformatted("code", Some("alt")),
+                        "```alt\ncode\n```"
+                    ),
+                    (GemtextToken::Link("/a", None), "=> /a"),
+                    (
+                        GemtextToken::Preformatted("unclosed", None),
+                        "```\nunclosed"
+                    ),
+                ]
+            );
+        }
+
+        #[test]
+        fn lone_fence_at_end() {
+            for src in ["Text\n```rust", "Text\n```rust\n"] {
+                assert_eq!(
+                    Gemtext::new(src).collect::<Vec<_>>(),
+                    [
+                        GemtextToken::Text("Text"),
+                        GemtextToken::Preformatted("", Some("rust")),
+                    ]
+                );
+            }
+            assert_eq!(
+                Gemtext::new("```").collect::<Vec<_>>(),
+                [GemtextToken::Preformatted("", None)]
+            );
+        }
+
+        #[test]
+        fn preformatted_alt_text_trimmed() {
+            let src = "```  rust \t\ncode\n```\n```   \n```\n";
+            assert_eq!(
+                Gemtext::new(src).collect::<Vec<_>>(),
+                [
+                    GemtextToken::Preformatted("code", Some("rust")),
+                    GemtextToken::Preformatted("", None),
+                ]
+            );
+        }
+
+        #[test]
+        fn index_text_for_search() {
+            let src = "# My  Capsule\n\nWelcome\tto my\r\ncapsule.\n=> gemini://example.com/about About   me\n=> /bare\n* one\n*  two\n> quoted words\n```ascii art\n  /\\_/\\\n```\nThe end.  \n";
+            assert_eq!(
+                index_text(src),
+                "My Capsule Welcome to my capsule. About me one two quoted words The end."
+            );
+            assert_eq!(index_text(""), "");
+        }
+
+        #[test]
+        fn rewrite_links_only() {
+            let src = "# gemini://example.com/\r\n=>\tgemini://example.com/a  Name \r\n=> b\ngemini://example.com/c\n```\n=> gemini://example.com/d\n```\n=> gemini://example.com/e";
+            let mut seen = Vec::new();
+            let rewritten = rewrite_links(src, |url| {
+                seen.push(url.to_string());
+                url.replace("gemini://example.com/", "/mirror/")
+            });
+            assert_eq!(
+                rewritten,
+                "# gemini://example.com/\r\n=>\t/mirror/a  Name \r\n=> b\ngemini://example.com/c\n```\n=> gemini://example.com/d\n```\n=> /mirror/e"
+            );
+            assert_eq!(
+                seen,
+                ["gemini://example.com/a", "b", "gemini://example.com/e"]
+            );
+            assert_eq!(rewrite_links("", |_| unreachable!()), "");
+        }
+
+        #[test]
+        fn code_blocks_with_alt_text() {
+            let src = "# Snippets\n```rust\nfn main() {}\n```\nSome text\n```\n$ cargo run\nHello\n```\n";
+            let blocks = code_blocks(src);
+            assert_eq!(
+                blocks,
+                vec![
+                    (Some("rust"), "fn main() {}".to_string()),
+                    (None, "$ cargo run\nHello".to_string()),
+                ]
+            );
+        }
+    }
+}
+pub mod uri {
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum Error {
+        /// The URI contains characters that can never appear in a URI, such as
+        /// ASCII control characters.
+        #[error("URI failed to validate")]
+        Invalid,
+    }
+
+    /// The port Gemini, and Titan along with it, is served on unless a URL
+    /// says otherwise.
+    pub const DEFAULT_PORT: u16 = 1965;
+
+    /// Why a URI can't be used as a Gemini request URL, see
+    /// [`Uri::validate_gemini_request`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+    pub enum GeminiUriError {
+        #[error("the scheme isn't gemini")]
+        WrongScheme,
+        #[error("there's no host")]
+        MissingHost,
+        #[error("it has userinfo")]
+        Userinfo,
+        #[error("the port isn't a number from 0 to 65535")]
+        BadPort,
+        #[error("it has a fragment")]
+        Fragment,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Uri<'a> {
+        pub scheme: Option<&'a str>,
+        pub userinfo: Option<&'a str>,
+        /// `None` if there's no authority at all, and `Some("")` for an empty
+        /// one, as in `file:///etc/hosts`.
+        pub host: Option<&'a str>,
+        pub port: Option<&'a str>,
+        /// The path as written, so after an authority it starts with a `/`:
+        /// `None` for `gemini://host` and `Some("/")` for `gemini://host/`.
+        pub path: Option<&'a str>,
+        pub query: Option<&'a str>,
+        pub fragment: Option<&'a str>,
+    }
+
+    impl<'a> Uri<'a> {
+        pub fn new(mut src: &'a str) -> Result<Self, Error> {
+            if src.contains(|c: char| c.is_ascii_control()) {
+                return Err(Error::Invalid);
+            }
+            let mut uri = Uri {
+                scheme: None,
+                userinfo: None,
+                host: None,
+                port: None,
+                path: None,
+                query: None,
+                fragment: None,
+            };
+
+            if let Some((rest, frag)) = src.split_once('#') {
+                src = rest;
+                uri.fragment = Some(frag);
+            }
+            if let Some((rest, query)) = src.split_once('?') {
+                src = rest;
+                uri.query = Some(query);
+            }
+
+            if src.starts_with(char::is_alphabetic)
+                && let Some((scheme, rest)) = src.split_once(':')
+                && scheme.chars().all(is_scheme)
+            {
+                uri.scheme = Some(scheme);
+                src = rest;
+            }
+
+            if let Some(rest) = src.strip_prefix("//") {
+                src = rest;
+                if let Some(i) = rest.find('/') {
+                    uri.path = Some(&rest[i..]);
+                    src = &rest[..i];
+                }
+
+                if let Some((userinfo, rest)) = src.split_once('@') {
+                    uri.userinfo = Some(userinfo);
+                    src = rest;
+                }
+                // An IP literal (`[2001:db8::7]`) is full of colons, so only one
+                // after the closing bracket can start the port.
+                let port_from = match src.starts_with('[') {
+                    true => src.find(']').unwrap_or(0),
+                    false => 0,
+                };
+                if let Some((host, port)) = src[port_from..].rsplit_once(':')
+                    && port.chars().all(|x| x.is_ascii_digit())
+                {
+                    uri.port = Some(port);
+                    src = &src[..port_from + host.len()];
+                }
+                uri.host = Some(src);
+            } else {
+                uri.path = Some(src);
+            }
+
+            Ok(uri)
+        }
+
+        /// Checks the requirements the Gemini spec (1.2) puts on request URLs
+        /// on top of RFC 3986: the scheme, if there is one, is `gemini`, there
+        /// is a non-empty host, a valid port if any, and no userinfo or
+        /// fragment.
+        pub fn validate_gemini_request(&self) -> Result<(), GeminiUriError> {
+            if self
+                .scheme
+                .is_some_and(|x| !x.eq_ignore_ascii_case("gemini"))
+            {
+                return Err(GeminiUriError::WrongScheme);
+            }
+            if self.host.is_none_or(str::is_empty) {
+                return Err(GeminiUriError::MissingHost);
+            }
+            if self.userinfo.is_some() {
+                return Err(GeminiUriError::Userinfo);
+            }
+            // An empty port (`gemini://host:/`) stands for the default one.
+            if self
+                .port
+                .is_some_and(|x| !x.is_empty() && parse_port(x).is_err())
+            {
+                return Err(GeminiUriError::BadPort);
+            }
+            if self.fragment.is_some() {
+                return Err(GeminiUriError::Fragment);
+            }
+            Ok(())
+        }
+
+        /// The port to connect to: the URI's own, or [`DEFAULT_PORT`] when it
+        /// has none.
+        ///
+        /// Only `gemini` and `titan` URIs (or ones without a scheme) have a
+        /// default, which an empty port (`gemini://host:/`) also stands for. A
+        /// port that isn't a number from 1 to 65535 is an error rather than
+        /// being replaced by the default.
+        pub fn port_or_default(&self) -> Result<u16, GeminiUriError> {
+            let has_default = |scheme: &str| {
+                scheme.eq_ignore_ascii_case("gemini")
+                    || scheme.eq_ignore_ascii_case("titan")
+            };
+            match self.port.filter(|x| !x.is_empty()) {
+                Some(port) => parse_port(port),
+                None if self.scheme.is_none_or(has_default) => Ok(DEFAULT_PORT),
+                None => Err(GeminiUriError::WrongScheme),
+            }
+        }
+
+        /// The fragment with percent-encoding undone, e.g. for matching it
+        /// against a heading to scroll to.
+        pub fn decoded_fragment(&self) -> Option<String> {
+            self.fragment.and_then(percent_decode)
+        }
+
+        /// Splits a `key=value&key2=value2` style query into percent-decoded
+        /// pairs. This isn't part of Gemini, but some applications use it.
+        ///
+        /// A key without a value (`?flag`) gets an empty value, and empty
+        /// segments are skipped. Parts that don't decode to UTF-8 are kept
+        /// as-is.
+        pub fn query_pairs(&self) -> impl Iterator<Item = (String, String)> + '_ {
+            let decode = |x: &str| percent_decode(x).unwrap_or_else(|| x.to_string());
+            self.query
+                .into_iter()
+                .flat_map(|query| query.split('&'))
+                .filter(|pair| !pair.is_empty())
+                .map(move |pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(key), decode(value))
+                })
+        }
+
+        /// Resolves this URI reference against `base` as described in RFC 3986
+        /// (5.2), e.g. to turn a relative link on a page into an absolute URL.
+        ///
+        /// Characters that aren't allowed in a path, like spaces, are
+        /// percent-encoded, so `my file.gmi` resolves to `.../my%20file.gmi`.
+        pub fn resolve(&self, base: &Uri) -> UriOwned {
+            let (authority, path, query) = if self.scheme.is_some() || self.host.is_some()
+            {
+                (self, remove_dot_segments(self.full_path()), self.query)
+            } else {
+                let path = self.full_path();
+                if path.is_empty() {
+                    (
+                        base,
+                        base.full_path().to_string(),
+                        self.query.or(base.query),
+                    )
+                } else if path.starts_with('/') {
+                    (base, remove_dot_segments(path), self.query)
+                } else {
+                    (base, remove_dot_segments(&merge(base, path)), self.query)
+                }
+            };
+            let path = encode_path(&path);
+            UriOwned {
+                scheme: self.scheme.or(base.scheme).map(String::from),
+                userinfo: authority.userinfo.map(String::from),
+                host: authority.host.map(String::from),
+                port: authority.port.map(String::from),
+                path: (!path.is_empty()).then_some(path),
+                query: query.map(String::from),
+                fragment: self.fragment.map(String::from),
+            }
+        }
+
+        /// The inverse of [`Uri::resolve`]: the shortest reference to `target`
+        /// that resolves against `self`, e.g. for emitting relative links.
+        ///
+        /// Returns `None` if `target` is on a different scheme or authority.
+        pub fn make_relative(&self, target: &Uri) -> Option<String> {
+            if (self.scheme, self.userinfo, self.host, self.port)
+                != (target.scheme, target.userinfo, target.host, target.port)
+            {
+                return None;
+            }
+            let base_path = self.full_path();
+            let target_path = target.full_path();
+            let base_dir: Vec<&str> = base_path.split('/').collect();
+            let base_dir = &base_dir[..base_dir.len() - 1];
+            let target_segments: Vec<&str> = target_path.split('/').collect();
+            // Only directories can be shared; the target's last segment names
+            // the resource itself, even if the base has a directory by that name.
+            let common = base_dir
+                .iter()
+                .zip(&target_segments[..target_segments.len() - 1])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            let mut relative = "../".repeat(base_dir.len() - common);
+            relative.push_str(&target_segments[common..].join("/"));
+            if relative.is_empty() || relative.split('/').next().unwrap().contains(':') {
+                // Keep the reference from reading as empty or as having a scheme.
+                relative.insert_str(0, "./");
+            }
+            let mut reference =
+                if target_path.starts_with('/') && target_path.len() < relative.len() {
+                    target_path.to_string()
+                } else {
+                    relative
+                };
+            if let Some(query) = target.query {
+                reference.push('?');
+                reference.push_str(query);
+            }
+            if let Some(fragment) = target.fragment {
+                reference.push('#');
+                reference.push_str(fragment);
+            }
+            Some(reference)
+        }
+
+        /// Normalizes the URI as described in RFC 3986 (6.2.2), so that URLs
+        /// which differ only in spelling compare equal, e.g. for a crawler
+        /// deduplicating links.
+        ///
+        /// The scheme and host are lowercased, percent-encodings are
+        /// uppercased and decoded where they stand for an unreserved
+        /// character, dot segments are removed (unless the path is relative),
+        /// an empty path after a host becomes `/`, and an empty port or a
+        /// `gemini` URI's default port of 1965 is dropped.
+        pub fn normalize(&self) -> UriOwned {
+            let scheme = self.scheme.map(str::to_ascii_lowercase);
+            let default_port = scheme.as_deref() == Some("gemini")
+                && self
+                    .port
+                    .is_some_and(|x| x.parse::<u16>() == Ok(DEFAULT_PORT));
+            let mut path = normalize_percent_encoding(self.full_path());
+            // A relative path is yet to be merged with a base, see `resolve`.
+            if self.scheme.is_some() || self.host.is_some() {
+                path = remove_dot_segments(&path);
+            }
+            UriOwned {
+                userinfo: self.userinfo.map(normalize_percent_encoding),
+                host: self.host.map(str::to_ascii_lowercase),
+                port: self
+                    .port
+                    .filter(|x| !x.is_empty() && !default_port)
+                    .map(String::from),
+                path: if self.host.is_some() && path.is_empty() {
+                    Some("/".to_string())
+                } else {
+                    (!path.is_empty()).then_some(path)
+                },
+                query: self.query.map(normalize_percent_encoding),
+                fragment: self.fragment.map(normalize_percent_encoding),
+                scheme,
+            }
+        }
+
+        /// The path, which is empty if there's none.
+        fn full_path(&self) -> &'a str {
+            self.path.unwrap_or_default()
+        }
+    }
+
+    /// Percent-encodes whatever isn't allowed in a path (RFC 3986 3.3), e.g.
+    /// the space in a hand-written `=> my file.gmi` link. Existing `%XX`
+    /// escapes are left alone.
+    fn encode_path(path: &str) -> String {
+        let mut out = String::with_capacity(path.len());
+        for (i, byte) in path.bytes().enumerate() {
+            let escape = byte == b'%'
+                && path
+                    .get(i + 1..i + 3)
+                    .is_some_and(|x| x.bytes().all(|x| x.is_ascii_hexdigit()));
+            if escape
+                || byte.is_ascii_alphanumeric()
+                || b"-._~!$&'()*+,;=:@/".contains(&byte)
+            {
+                out.push(char::from(byte));
+            } else {
+                out.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        out
+    }
+
+    // SEE: RFC 3986 5.2.3
+    fn merge(base: &Uri, path: &str) -> String {
+        let base_path = base.full_path();
+        if base.host.is_some() && base_path.is_empty() {
+            format!("/{path}")
+        } else {
+            let dir = base_path.rfind('/').map_or("", |i| &base_path[..=i]);
+            format!("{dir}{path}")
+        }
+    }
+
+    /// Parses a port that can be connected to, so not 0.
+    fn parse_port(port: &str) -> Result<u16, GeminiUriError> {
+        match port.parse() {
+            Ok(0) | Err(_) => Err(GeminiUriError::BadPort),
+            Ok(port) => Ok(port),
+        }
+    }
+
+    /// Collapses the `.` and `..` segments of `path` as described in RFC 3986
+    /// (5.2.4), e.g. `/a/b/c/./../../g` becomes `/a/g`.
+    ///
+    /// Escapes of unreserved characters are decoded first, so `%2e%2e` counts as
+    /// `..`. A `..` can never climb above the root, so servers can use this to
+    /// canonicalize a request path before mapping it to the filesystem.
+    pub fn remove_dot_segments(path: &str) -> String {
+        let pop_segment = |output: &mut String| {
+            output.truncate(output.rfind('/').unwrap_or(0));
+        };
+        let path = normalize_percent_encoding(path);
+        let mut input = path.as_str();
+        let mut output = String::new();
+        while !input.is_empty() {
+            if let Some(rest) = input.strip_prefix("../") {
+                input = rest;
+            } else if let Some(rest) = input.strip_prefix("./") {
+                input = rest;
+            } else if input.starts_with("/./") {
+                input = &input[2..];
+            } else if input == "/." {
+                input = "/";
+            } else if input.starts_with("/../") {
+                input = &input[3..];
+                pop_segment(&mut output);
+            } else if input == "/.." {
+                input = "/";
+                pop_segment(&mut output);
+            } else if input == "." || input == ".." {
+                input = "";
+            } else {
+                let start = usize::from(input.starts_with('/'));
+                let end = input[start..].find('/').map_or(input.len(), |i| i + start);
+                output.push_str(&input[..end]);
+                input = &input[end..];
+            }
+        }
+        output
+    }
+
+    impl<'a> From<&'a UriOwned> for Uri<'a> {
+        fn from(uri: &'a UriOwned) -> Self {
+            Self {
+                scheme: uri.scheme.as_deref(),
+                userinfo: uri.userinfo.as_deref(),
+                host: uri.host.as_deref(),
+                port: uri.port.as_deref(),
+                path: uri.path.as_deref(),
+                query: uri.query.as_deref(),
+                fragment: uri.fragment.as_deref(),
+            }
+        }
+    }
+
+    impl std::fmt::Display for Uri<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+            if let Some(scheme) = self.scheme {
+                write!(f, "{scheme}")?;
+                write!(f, ":")?;
+            }
+
+            if self.host.is_some() || self.userinfo.is_some() || self.port.is_some() {
+                write!(f, "//")?;
+                if let Some(userinfo) = self.userinfo {
+                    write!(f, "{userinfo}")?;
+                    write!(f, "@")?;
+                }
+                if let Some(host) = self.host {
+                    write!(f, "{host}")?;
+                }
+                if let Some(port) = self.port {
+                    write!(f, ":")?;
+                    write!(f, "{port}")?;
+                }
+                if let Some(path) = self.path {
+                    // A path after an authority has to start with a `/`, which
+                    // one put together by hand might have left out.
+                    if !path.is_empty() && !path.starts_with('/') {
+                        write!(f, "/")?;
+                    }
+                    write!(f, "{path}")?;
+                }
+            } else if let Some(path) = self.path {
+                // Without an authority a path starting with `//` would be read
+                // back as one, so it's written as `/.//` (RFC 3986 5.3).
+                if path.starts_with("//") {
+                    write!(f, "/.")?;
+                }
+                write!(f, "{path}")?;
+            }
+            if let Some(query) = self.query {
+                write!(f, "?")?;
+                write!(f, "{query}")?;
+            }
+            if let Some(fragment) = self.fragment {
+                write!(f, "#")?;
+                write!(f, "{fragment}")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// An owned version of [`Uri`]. Like [`Uri`] it compares and hashes
+    /// component by component, so URLs that only differ in spelling, like
+    /// `gemini://host/` and `GEMINI://host/`, are different keys unless
+    /// [normalized](Uri::normalize) first.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct UriOwned {
+        pub scheme: Option<String>,
+        pub userinfo: Option<String>,
+        pub host: Option<String>,
+        pub port: Option<String>,
+        pub path: Option<String>,
+        pub query: Option<String>,
+        pub fragment: Option<String>,
+    }
+
+    impl From<Uri<'_>> for UriOwned {
+        fn from(uri: Uri) -> Self {
+            Self {
+                scheme: uri.scheme.map(String::from),
+                userinfo: uri.userinfo.map(String::from),
+                host: uri.host.map(String::from),
+                port: uri.port.map(String::from),
+                path: uri.path.map(String::from),
+                query: uri.query.map(String::from),
+                fragment: uri.fragment.map(String::from),
+            }
+        }
+    }
+
+    impl UriOwned {
+        pub fn as_ref(&self) -> Uri<'_> {
+            self.into()
+        }
+    }
+
+    impl std::fmt::Display for UriOwned {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+            let uri: Uri = self.into();
+            write!(f, "{uri}")
+        }
+    }
+
+    /// Uppercases the hex digits of the `%XX` escapes in `s` and decodes those
+    /// that stand for an unreserved character.
+    fn normalize_percent_encoding(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('%') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            let byte = rest
+                .get(1..3)
+                .filter(|x| x.bytes().all(|x| x.is_ascii_hexdigit()))
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            match byte {
+                Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                    out.push(char::from(byte));
+                }
+                Some(byte) => out.push_str(&format!("%{byte:02X}")),
+                // Not an escape after all, leave it be.
+                None => {
+                    out.push('%');
+                    rest = &rest[1..];
+                    continue;
+                }
+            }
+            rest = &rest[3..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Remembers the results of [`Uri::normalize`] by the raw URL, for
+    /// crawlers that see the same links over and over.
+    ///
+    /// Holds at most `capacity` URLs (but always at least one), forgetting the
+    /// least recently used one to make room for a new one.
+    #[derive(Debug, Clone)]
+    pub struct NormalizedCache {
+        capacity: usize,
+        /// The normalized URIs by raw URL, along with when they were last used.
+        entries: HashMap<String, (UriOwned, u64)>,
+        /// The raw URLs by when they were last used, oldest first.
+        recency: BTreeMap<u64, String>,
+        clock: u64,
+        hits: usize,
+        misses: usize,
+    }
+
+    impl NormalizedCache {
+        pub fn new(capacity: usize) -> Self {
+            Self {
+                capacity: capacity.max(1),
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }
+        }
+
+        /// Parses and normalizes `url`, or returns the result from the last
+        /// time it was seen.
+        pub fn normalize(&mut self, url: &str) -> Result<&UriOwned, Error> {
+            self.clock += 1;
+            if let Some((_, used)) = self.entries.get_mut(url) {
+                self.hits += 1;
+                let key = self.recency.remove(used).expect("entries are in recency");
+                *used = self.clock;
+                self.recency.insert(self.clock, key);
+            } else {
+                self.misses += 1;
+                let normalized = Uri::new(url)?.normalize();
+                if self.entries.len() >= self.capacity {
+                    let (_, oldest) = self.recency.pop_first().expect("cache is full");
+                    self.entries.remove(&oldest);
+                }
+                self.entries
+                    .insert(url.to_string(), (normalized, self.clock));
+                self.recency.insert(self.clock, url.to_string());
+            }
+            Ok(&self.entries[url].0)
+        }
+
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        /// How many calls to [`normalize`](Self::normalize) found the URL
+        /// already cached.
+        pub fn hits(&self) -> usize {
+            self.hits
+        }
+
+        /// How many calls to [`normalize`](Self::normalize) had to parse the
+        /// URL.
+        pub fn misses(&self) -> usize {
+            self.misses
+        }
+    }
+
+    fn is_scheme(c: char) -> bool {
+        c.is_alphabetic() || c.is_ascii_digit() || "+-.".contains(c)
+    }
+
+    /// Decodes the `%XX` escapes in `s`, treating the escaped bytes as UTF-8.
+    ///
+    /// Returns `None` if an escape is malformed or the decoded bytes aren't valid
+    /// UTF-8.
+    pub fn percent_decode(s: impl AsRef<str>) -> Option<String> {
+        let mut bytes = s.as_ref().bytes();
+        let mut out = Vec::new();
+        while let Some(byte) = bytes.next() {
+            if byte == b'%' {
+                let hi = char::from(bytes.next()?).to_digit(16)?;
+                let lo = char::from(bytes.next()?).to_digit(16)?;
+                out.push((hi * 16 + lo) as u8);
+            } else {
+                out.push(byte);
+            }
+        }
+        String::from_utf8(out).ok()
+    }
+
+    /// Percent-encodes every byte of `s` that isn't in the RFC 3986 unreserved
+    /// set (`A-Za-z0-9-._~`) as an uppercase `%XX` escape. Multi-byte characters
+    /// are encoded one UTF-8 byte at a time.
+    pub fn percent_encode(s: impl AsRef<str>) -> String {
+        percent_encode_set(s.as_ref(), &[])
+    }
+
+    /// Bytes that may appear unencoded in a path, on top of the unreserved
+    /// set: the sub-delims, `:`, `@` and `/`.
+    pub const PATH: &[u8] = b"!$&'()*+,;=:@/";
+    /// Bytes that may appear unencoded in a query: those of [`PATH`] and `?`.
+    pub const QUERY: &[u8] = b"!$&'()*+,;=:@/?";
+    /// Bytes that may appear unencoded in a fragment, the same as [`QUERY`].
+    pub const FRAGMENT: &[u8] = QUERY;
+
+    /// Like [`percent_encode`], but leaves the bytes in `keep` unencoded too,
+    /// e.g. [`PATH`] to encode a path without escaping its slashes.
+    pub fn percent_encode_set(s: &str, keep: &[u8]) -> String {
+        let mut out = String::new();
+        for byte in s.bytes() {
+            if byte.is_ascii_alphanumeric()
+                || b"-._~".contains(&byte)
+                || keep.contains(&byte)
+            {
+                out.push(char::from(byte));
+            } else {
+                out.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn percent() {
+            assert_eq!(
+            percent_decode("%21%40%23%24%25%2A%28%29With Some Text in the middle%7E%7B%7D%3A%3C%3E%3F_%2B").unwrap(),
+            "!@#$%*()With Some Text in the middle~{}:<>?_+");
+        }
+
+        #[test]
+        fn percent_decode_utf8() {
+            assert_eq!(percent_decode("%E2%9C%93").unwrap(), "✓");
+            assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+            assert_eq!(percent_decode("%C3"), None);
+            assert_eq!(percent_decode("%zz"), None);
+            assert_eq!(percent_decode("%4"), None);
+            let s = "Ünïcödé ✓ & friends";
+            assert_eq!(percent_decode(percent_encode(s)).unwrap(), s);
+        }
+
+        #[test]
+        fn percent_encoding() {
+            assert_eq!(percent_encode("a b&c=d/e~f"), "a%20b%26c%3Dd%2Fe~f");
+            assert_eq!(percent_encode("Unreserved-._~"), "Unreserved-._~");
+            assert_eq!(percent_encode("é ✓"), "%C3%A9%20%E2%9C%93");
+            assert_eq!(percent_encode("\r\n\u{0}"), "%0D%0A%00");
+        }
+
+        #[test]
+        fn percent_encoding_sets() {
+            let s = "a b/c?d=e&f#g";
+            assert_eq!(percent_encode_set(s, PATH), "a%20b/c%3Fd=e&f%23g");
+            assert_eq!(percent_encode_set(s, QUERY), "a%20b/c?d=e&f%23g");
+            assert_eq!(percent_encode_set(s, FRAGMENT), "a%20b/c?d=e&f%23g");
+            assert_eq!(percent_encode_set(s, &[]), percent_encode(s));
+            assert_eq!(percent_encode_set("é%", PATH), "%C3%A9%25");
+        }
+
+        #[test]
+        fn uri() {
+            let test1 = "ftp://ftp.is.co.za/rfc/rfc1808.txt";
+            let test2 = "http://www.ietf.org/rfc/rfc2396.txt";
+            let test3 = "ldap://[2001:db8::7]/c=GB?objectClass?one";
+            let test4 = "mailto:John.Doe@example.com";
+            let test5 = "news:comp.infosystems.www.servers.unix";
+            let test6 = "tel:+1-816-555-1212";
+            let test7 = "telnet://192.0.2.16:80/";
+            let test8 = "urn:oasis:names:specification:docbook:dtd:xml:4.1.2";
+            let test9 = "https://datatracker.ietf.org/doc/html/rfc3986#section-1.1.2";
+            let test10 = "https://www.youtube.com/watch?v=QyjyWUrHsFc";
+            let test11 =
+                "https://john.doe@www.example.com:1234/forum/questions/?query#Frag";
+            Uri::new(test1).unwrap();
+            Uri::new(test2).unwrap();
+            let uri3 = Uri::new(test3).unwrap();
+            assert_eq!(uri3.host, Some("[2001:db8::7]"));
+            assert_eq!(uri3.port, None);
+            assert_eq!(uri3.path, Some("/c=GB"));
+            Uri::new(test4).unwrap();
+            Uri::new(test5).unwrap();
+            Uri::new(test6).unwrap();
+            Uri::new(test7).unwrap();
+            let uri2 = Uri::new(test8).unwrap();
+            assert_eq!(
+                uri2.path,
+                Some("oasis:names:specification:docbook:dtd:xml:4.1.2")
+            );
+            Uri::new(test9).unwrap();
+            Uri::new(test10).unwrap();
+            let uri = Uri::new(test11).unwrap();
+            assert_eq!(uri.scheme, Some("https"));
+            assert_eq!(uri.userinfo, Some("john.doe"));
+            assert_eq!(uri.host, Some("www.example.com"));
+            assert_eq!(uri.port, Some("1234"));
+            assert_eq!(uri.path, Some("/forum/questions/"));
+            assert_eq!(uri.query, Some("query"));
+            assert_eq!(uri.fragment, Some("Frag"));
+        }
+
+        #[test]
+        fn ip_literal_hosts() {
+            let uri = Uri::new("gemini://[::1]:1965/page.gmi").unwrap();
+            assert_eq!(uri.host, Some("[::1]"));
+            assert_eq!(uri.port, Some("1965"));
+            assert_eq!(uri.path, Some("/page.gmi"));
+            let uri = Uri::new("gemini://user:pass@[2001:db8::1]/").unwrap();
+            assert_eq!(uri.userinfo, Some("user:pass"));
+            assert_eq!(uri.host, Some("[2001:db8::1]"));
+            assert_eq!(uri.port, None);
+            let uri = Uri::new("gemini://[2001:db8::1]:70").unwrap();
+            assert_eq!(uri.host, Some("[2001:db8::1]"));
+            assert_eq!(uri.port, Some("70"));
+            let uri = Uri::new("gemini://user:1@example.com:70").unwrap();
+            assert_eq!(uri.userinfo, Some("user:1"));
+            assert_eq!(uri.host, Some("example.com"));
+            assert_eq!(uri.port, Some("70"));
+        }
+
+        #[test]
+        fn opaque_path_with_query() {
+            // The query and fragment are split off before the scheme, so they
+            // never end up in an opaque path.
+            let uri = Uri::new("mailto:John.Doe@example.com?subject=Hi#top").unwrap();
+            assert_eq!(uri.scheme, Some("mailto"));
+            assert_eq!(uri.userinfo, None);
+            assert_eq!(uri.host, None);
+            assert_eq!(uri.path, Some("John.Doe@example.com"));
+            assert_eq!(uri.query, Some("subject=Hi"));
+            assert_eq!(uri.fragment, Some("top"));
+            assert_eq!(
+                UriOwned::from(uri).to_string(),
+                "mailto:John.Doe@example.com?subject=Hi#top"
+            );
+        }
+
+        #[test]
+        fn decoded_fragment() {
+            let uri = Uri::new("gemini://example.com/page.gmi#a%20b").unwrap();
+            assert_eq!(uri.decoded_fragment().as_deref(), Some("a b"));
+            let uri = Uri::new("gemini://example.com/page.gmi").unwrap();
+            assert_eq!(uri.decoded_fragment(), None);
+        }
+
+        #[test]
+        fn query_pairs() {
+            let pairs =
+                |uri: &str| Uri::new(uri).unwrap().query_pairs().collect::<Vec<_>>();
+            let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+            assert_eq!(
+                pairs("gemini://example.com/app?name=J%C3%BCrgen&page=2&&q=a%26b%3Dc"),
+                [
+                    pair("name", "Jürgen"),
+                    pair("page", "2"),
+                    pair("q", "a&b=c")
+                ]
+            );
+            assert_eq!(
+                pairs("gemini://example.com/app?flag&x="),
+                [pair("flag", ""), pair("x", "")]
+            );
+            assert_eq!(pairs("gemini://example.com/app"), []);
+        }
+
+        #[test]
+        fn port_or_default() {
+            let port = |src| Uri::new(src).unwrap().port_or_default();
+            assert_eq!(port("gemini://example.com/"), Ok(1965));
+            assert_eq!(port("//example.com/"), Ok(1965));
+            assert_eq!(port("gemini://example.com:1966/"), Ok(1966));
+            assert_eq!(port("https://example.com:443/"), Ok(443));
+            assert_eq!(port("gemini://[::1]/"), Ok(1965));
+            assert_eq!(port("TITAN://example.com/"), Ok(DEFAULT_PORT));
+            assert_eq!(port("gemini://example.com:/"), Ok(DEFAULT_PORT));
+            assert_eq!(
+                port("gemini://example.com:0/"),
+                Err(GeminiUriError::BadPort)
+            );
+            assert_eq!(
+                port("gemini://example.com:99999/"),
+                Err(GeminiUriError::BadPort)
+            );
+            assert_eq!(
+                port("https://example.com/"),
+                Err(GeminiUriError::WrongScheme)
+            );
+        }
+
+        #[test]
+        fn validate_gemini_request() {
+            let validate = |src| Uri::new(src).unwrap().validate_gemini_request();
+            assert_eq!(validate("gemini://example.com/"), Ok(()));
+            assert_eq!(validate("GEMINI://example.com:1965/a?b"), Ok(()));
+            assert_eq!(validate("//example.com/"), Ok(()));
+            assert_eq!(
+                validate("https://example.com/"),
+                Err(GeminiUriError::WrongScheme)
+            );
+            assert_eq!(validate("gemini:/path"), Err(GeminiUriError::MissingHost));
+            assert_eq!(validate("gemini:///path"), Err(GeminiUriError::MissingHost));
+            assert_eq!(
+                validate("gemini://user@example.com/"),
+                Err(GeminiUriError::Userinfo)
+            );
+            assert_eq!(
+                validate("gemini://example.com:99999/"),
+                Err(GeminiUriError::BadPort)
+            );
+            assert_eq!(
+                validate("gemini://example.com:0/"),
+                Err(GeminiUriError::BadPort)
+            );
+            assert_eq!(validate("gemini://example.com:/"), Ok(()));
+            assert_eq!(
+                validate("gemini://example.com/#top"),
+                Err(GeminiUriError::Fragment)
+            );
+        }
+
+        #[test]
+        fn dot_segments() {
+            // RFC 3986 (5.2.4)
+            assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+            assert_eq!(remove_dot_segments("mid/content=5/../6"), "mid/6");
+            assert_eq!(remove_dot_segments("/../../etc/passwd"), "/etc/passwd");
+            assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+            assert_eq!(remove_dot_segments("/a/./b/."), "/a/b/");
+            assert_eq!(remove_dot_segments("/a/..b/c."), "/a/..b/c.");
+            assert_eq!(remove_dot_segments(".."), "");
+            assert_eq!(remove_dot_segments(""), "");
+            assert_eq!(
+                remove_dot_segments("/a/%2e%2e/%2E%2e/etc/passwd"),
+                "/etc/passwd"
+            );
+            assert_eq!(remove_dot_segments("/a/%2e/b"), "/a/b");
+            assert_eq!(remove_dot_segments("/a/%2f../b"), "/a/%2F../b");
+        }
+
+        #[test]
+        fn resolve_references() {
+            // RFC 3986 (5.4.1)
+            let base = Uri::new("http://a/b/c/d;p?q").unwrap();
+            for (reference, target) in [
+                ("g:h", "g:h"),
+                ("g", "http://a/b/c/g"),
+                ("./g", "http://a/b/c/g"),
+                ("g/", "http://a/b/c/g/"),
+                ("/g", "http://a/g"),
+                ("//g", "http://g"),
+                ("?y", "http://a/b/c/d;p?y"),
+                ("g?y", "http://a/b/c/g?y"),
+                ("#s", "http://a/b/c/d;p?q#s"),
+                ("g?y#s", "http://a/b/c/g?y#s"),
+                (";x", "http://a/b/c/;x"),
+                ("", "http://a/b/c/d;p?q"),
+                (".", "http://a/b/c/"),
+                ("./", "http://a/b/c/"),
+                ("..", "http://a/b/"),
+                ("../", "http://a/b/"),
+                ("../g", "http://a/b/g"),
+                ("../..", "http://a/"),
+                ("../../", "http://a/"),
+                ("../../g", "http://a/g"),
+                // (5.4.2)
+                ("../../../g", "http://a/g"),
+                ("/./g", "http://a/g"),
+                ("/../g", "http://a/g"),
+                ("g.", "http://a/b/c/g."),
+                ("..g", "http://a/b/c/..g"),
+                ("./g/.", "http://a/b/c/g/"),
+                ("g/../h", "http://a/b/c/h"),
+            ] {
+                let resolved = Uri::new(reference).unwrap().resolve(&base);
+                assert_eq!(resolved.to_string(), target, "resolving {reference:?}");
+            }
+
+            let base = Uri::new("gemini://example.com/docs/").unwrap();
+            let resolve =
+                |reference| Uri::new(reference).unwrap().resolve(&base).to_string();
+            assert_eq!(resolve("../index.gmi"), "gemini://example.com/index.gmi");
+            assert_eq!(resolve("./faq.gmi"), "gemini://example.com/docs/faq.gmi");
+            assert_eq!(resolve("/about.gmi"), "gemini://example.com/about.gmi");
+            assert_eq!(resolve("?query"), "gemini://example.com/docs/?query");
+            assert_eq!(
+                resolve("my file.gmi"),
+                "gemini://example.com/docs/my%20file.gmi"
+            );
+            assert_eq!(
+                resolve("100%25 done/é.gmi"),
+                "gemini://example.com/docs/100%25%20done/%C3%A9.gmi"
+            );
+        }
+
+        #[test]
+        fn normalize() {
+            let normalize = |src| Uri::new(src).unwrap().normalize().to_string();
+            assert_eq!(
+                normalize("GEMINI://Example.COM:1965/a/./b/../%7ec%2f?q=%3a#F%41"),
+                "gemini://example.com/a/~c%2F?q=%3A#FA"
+            );
+            assert_eq!(normalize("gemini://example.com"), "gemini://example.com/");
+            assert_eq!(normalize("gemini://example.com:/"), "gemini://example.com/");
+            assert_eq!(
+                normalize("gemini://example.com:1966/"),
+                "gemini://example.com:1966/"
+            );
+            assert_eq!(
+                normalize("https://example.com:1965/"),
+                "https://example.com:1965/"
+            );
+            assert_eq!(normalize("a/../b/%zz%2e"), "a/../b/%zz.");
+        }
+
+        #[test]
+        fn normalized_cache() {
+            let mut cache = NormalizedCache::new(2);
+            let first = cache
+                .normalize("GEMINI://Example.com/a/../b")
+                .unwrap()
+                .clone();
+            assert_eq!(first.to_string(), "gemini://example.com/b");
+            assert_eq!((cache.hits(), cache.misses()), (0, 1));
+            assert_eq!(
+                cache.normalize("GEMINI://Example.com/a/../b").unwrap(),
+                &first
+            );
+            assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+            // The least recently used URL makes room for new ones.
+            cache.normalize("gemini://example.com/c").unwrap();
+            cache.normalize("GEMINI://Example.com/a/../b").unwrap();
+            cache.normalize("gemini://example.com/d").unwrap();
+            assert_eq!(cache.len(), 2);
+            cache.normalize("GEMINI://Example.com/a/../b").unwrap();
+            assert_eq!((cache.hits(), cache.misses()), (3, 3));
+            cache.normalize("gemini://example.com/c").unwrap();
+            assert_eq!((cache.hits(), cache.misses()), (3, 4));
+
+            assert!(cache.normalize("gemini://exa\0mple.com/").is_err());
+        }
+
+        #[test]
+        fn make_relative() {
+            for (base, target, expected) in [
+                ("/a/b/page.gmi", "/a/b/other.gmi", "other.gmi"),
+                ("/a/b/page.gmi", "/a/b/sub/child.gmi?q", "sub/child.gmi?q"),
+                ("/a/b/page.gmi", "/a/parent.gmi", "../parent.gmi"),
+                ("/a/b/page.gmi", "/x", "/x"),
+                ("/a/b/page.gmi", "/a/b/", "./"),
+                ("/a/b/c", "/a/b", "../b"),
+                ("/a/b/c", "/a/b/", "./"),
+            ] {
+                let base = format!("gemini://example.com{base}");
+                let base = Uri::new(&base).unwrap();
+                let target = format!("gemini://example.com{target}");
+                let reference = base.make_relative(&Uri::new(&target).unwrap()).unwrap();
+                assert_eq!(reference, expected, "{target}");
+                let resolved = Uri::new(&reference).unwrap().resolve(&base);
+                assert_eq!(resolved.to_string(), target);
+            }
+            let base = Uri::new("gemini://example.com/a/b/page.gmi").unwrap();
+            let target = Uri::new("gemini://example.org/a/b/other.gmi").unwrap();
+            assert_eq!(base.make_relative(&target), None);
+        }
+
+        #[test]
+        fn round_trip() {
+            for src in [
+                "gemini://host",
+                "gemini://host/",
+                "gemini://host//",
+                "gemini://host//a//b/",
+                "gemini://host:/",
+                "gemini://@host/",
+                "file:///etc/hosts",
+                "file://",
+                "file:",
+                "//",
+                "///a",
+                "/a/",
+                "a/b/",
+                "",
+                "?",
+                "#",
+                "gemini://host/?#",
+                "a:/.//b",
+            ] {
+                let uri = Uri::new(src).unwrap();
+                assert_eq!(uri.to_string(), src);
+                assert_eq!(UriOwned::from(uri).to_string(), src);
+            }
+            let uri = Uri::new("file:///etc/hosts").unwrap();
+            assert_eq!(uri.host, Some(""));
+            assert_eq!(uri.path, Some("/etc/hosts"));
+            let uri = Uri::new("file:/etc/hosts").unwrap();
+            assert_eq!(uri.host, None);
+            assert_eq!(uri.path, Some("/etc/hosts"));
+            // A path that would read as an authority keeps its meaning.
+            let uri = Uri {
+                path: Some("//a"),
+                ..Uri::new("x:").unwrap()
+            };
+            assert_eq!(uri.to_string(), "x:/.//a");
+            assert_eq!(Uri::new("x:/.//a").unwrap().full_path(), "/.//a");
+        }
+
+        #[test]
+        fn path_keeps_leading_slash() {
+            for (src, path) in [
+                ("gemini://host", None),
+                ("gemini://host/", Some("/")),
+                ("gemini://host//a", Some("//a")),
+                ("gemini://host:1965/a/b", Some("/a/b")),
+                ("gemini://host?q", None),
+            ] {
+                let uri = Uri::new(src).unwrap();
+                assert_eq!(uri.path, path, "{src}");
+                assert_eq!(uri.to_string(), src);
+            }
+            // A path put together by hand still gets its separator.
+            let uri = Uri {
+                path: Some("a"),
+                ..Uri::new("gemini://host").unwrap()
+            };
+            assert_eq!(uri.to_string(), "gemini://host/a");
+        }
+
+        #[test]
+        fn uri_owned_as_key() {
+            let owned = |src| UriOwned::from(Uri::new(src).unwrap());
+            let mut visited = std::collections::HashSet::new();
+            assert!(visited.insert(owned("gemini://host/a")));
+            assert!(!visited.insert(owned("gemini://host/a")));
+            assert!(visited.insert(owned("gemini://host/a?")));
+            assert!(visited.insert(owned("GEMINI://host/a")));
+            assert!(visited.contains(&Uri::new("gemini://host/a").unwrap().normalize()));
+        }
+
+        #[test]
+        fn uri_owned() {
+            let test1 = "https://www.youtube.com/watch?v=QyjyWUrHsFc";
+            let test2 = "http://www.ietf.org/rfc/rfc2396.txt";
+            let test3 = "ldap://[2001:db8::7]/c=GB?objectClass?one";
+            let test4 = "mailto:John.Doe@example.com";
+            let test5 = "news:comp.infosystems.www.servers.unix";
+            let test6 = "tel:+1-816-555-1212";
             let test7 = "telnet://192.0.2.16:80/";
             let test8 = "urn:oasis:names:specification:docbook:dtd:xml:4.1.2";
 
-            let uri1 = Uri::new(test1).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri1)).to_string(), test1);
-            let uri2 = Uri::new(test2).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri2)).to_string(), test2);
-            let uri3 = Uri::new(test3).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri3)).to_string(), test3);
-            let uri4 = Uri::new(test4).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri4)).to_string(), test4);
-            let uri5 = Uri::new(test5).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri5)).to_string(), test5);
-            let uri6 = Uri::new(test6).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri6)).to_string(), test6);
-            let uri7 = Uri::new(test7).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri7)).to_string(), test7);
-            let uri8 = Uri::new(test8).unwrap();
-            assert_eq!(UriOwned::from(dbg!(uri8)).to_string(), test8);
+            let uri1 = Uri::new(test1).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri1)).to_string(), test1);
+            let uri2 = Uri::new(test2).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri2)).to_string(), test2);
+            let uri3 = Uri::new(test3).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri3)).to_string(), test3);
+            let uri4 = Uri::new(test4).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri4)).to_string(), test4);
+            let uri5 = Uri::new(test5).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri5)).to_string(), test5);
+            let uri6 = Uri::new(test6).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri6)).to_string(), test6);
+            let uri7 = Uri::new(test7).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri7)).to_string(), test7);
+            let uri8 = Uri::new(test8).unwrap();
+            assert_eq!(UriOwned::from(dbg!(uri8)).to_string(), test8);
+        }
+    }
+}
+
+pub mod cert {
+    use rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use rustls::crypto::WebPkiSupportedAlgorithms;
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    #[cfg(feature = "rcgen")]
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+    use std::time::{Duration, SystemTime};
+    use x509_parser::certificate::X509Certificate;
+    use x509_parser::extensions::GeneralName;
+
+    /// Reads the expiry (`notAfter`) date of a DER encoded X.509 certificate,
+    /// e.g. to warn that a pinned certificate is about to change.
+    ///
+    /// Returns `None` if the certificate can't be parsed.
+    pub fn expiry(der: &CertificateDer) -> Option<SystemTime> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let secs = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Generates a new self-signed certificate with the given common name and
+    /// its private key, e.g. to create an identity for
+    /// [`Client::with_identity`](crate::Client::with_identity) on the fly.
+    #[cfg(feature = "rcgen")]
+    pub fn generate_self_signed(
+        common_name: &str,
+    ) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), rcgen::Error> {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new())?;
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, common_name);
+        let key = rcgen::KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+        Ok((cert.into(), key.into()))
+    }
+
+    /// A certificate verifier for servers without a CA, which is most of
+    /// Gemini: it doesn't care who issued a certificate, but still refuses
+    /// one that has expired, isn't valid yet, or is for a different host.
+    ///
+    /// Install it with [`Client::with_expiry_checks`](crate::Client::with_expiry_checks)
+    /// or [`Client::with_verifier`](crate::Client::with_verifier). Handshake
+    /// signatures are checked as well.
+    #[derive(Debug)]
+    pub struct ValidityVerifier {
+        algorithms: WebPkiSupportedAlgorithms,
+    }
+
+    impl ValidityVerifier {
+        pub fn new() -> Self {
+            Self {
+                algorithms: crate::crypto_provider().signature_verification_algorithms,
+            }
+        }
+    }
+
+    impl Default for ValidityVerifier {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Whether the certificate is for `name`, going by its subject alternative
+    /// names or, without any, its common name.
+    fn matches_name(cert: &X509Certificate, name: &ServerName) -> bool {
+        let san = cert.subject_alternative_name().ok().flatten();
+        match name {
+            ServerName::DnsName(name) => {
+                let matches = |pattern: &str| dns_name_matches(pattern, name.as_ref());
+                match san {
+                    Some(san) => san.value.general_names.iter().any(|x| match x {
+                        GeneralName::DNSName(pattern) => matches(pattern),
+                        _ => false,
+                    }),
+                    None => cert
+                        .subject()
+                        .iter_common_name()
+                        .filter_map(|x| x.as_str().ok())
+                        .any(matches),
+                }
+            }
+            ServerName::IpAddress(ip) => {
+                let ip = match std::net::IpAddr::from(*ip) {
+                    std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+                    std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                san.is_some_and(|san| {
+                    san.value
+                        .general_names
+                        .iter()
+                        .any(|x| matches!(x, GeneralName::IPAddress(x) if *x == ip))
+                })
+            }
+            _ => false,
+        }
+    }
+
+    /// Compares DNS names case-insensitively, where a leading `*` label in
+    /// `pattern` stands for exactly one label of `name`.
+    fn dns_name_matches(pattern: &str, name: &str) -> bool {
+        let (pattern, name) = (pattern.trim_end_matches('.'), name.trim_end_matches('.'));
+        match pattern.strip_prefix("*.") {
+            Some(parent) => name.split_once('.').is_some_and(|(label, rest)| {
+                !label.is_empty() && rest.eq_ignore_ascii_case(parent)
+            }),
+            None => pattern.eq_ignore_ascii_case(name),
+        }
+    }
+
+    impl ServerCertVerifier for ValidityVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let invalid = rustls::Error::InvalidCertificate;
+            let (_, cert) = x509_parser::parse_x509_certificate(end_entity)
+                .map_err(|_| invalid(CertificateError::BadEncoding))?;
+            let now = i64::try_from(now.as_secs()).unwrap_or(i64::MAX);
+            let validity = cert.validity();
+            if now < validity.not_before.timestamp() {
+                return Err(invalid(CertificateError::NotValidYet));
+            }
+            if now > validity.not_after.timestamp() {
+                return Err(invalid(CertificateError::Expired));
+            }
+            if !matches_name(&cert, server_name) {
+                return Err(invalid(CertificateError::NotValidForName));
+            }
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn validity_verifier() {
+            let cert = |names: &[&str], not_before, not_after| {
+                let mut params = rcgen::CertificateParams::new(
+                    names.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+                )
+                .unwrap();
+                params.not_before = not_before;
+                params.not_after = not_after;
+                params
+                    .self_signed(&rcgen::KeyPair::generate().unwrap())
+                    .unwrap()
+            };
+            let verify = |cert: &rcgen::Certificate, host: &'static str| {
+                ValidityVerifier::new()
+                    .verify_server_cert(
+                        cert.der(),
+                        &[],
+                        &ServerName::try_from(host).unwrap(),
+                        &[],
+                        UnixTime::now(),
+                    )
+                    .map(|_| ())
+            };
+            let err = |e| Err(rustls::Error::InvalidCertificate(e));
+            let (past, future) = (
+                rcgen::date_time_ymd(2000, 1, 1),
+                rcgen::date_time_ymd(3000, 1, 1),
+            );
+
+            let valid =
+                cert(&["example.com", "*.example.org", "127.0.0.1"], past, future);
+            assert_eq!(verify(&valid, "example.com"), Ok(()));
+            assert_eq!(verify(&valid, "EXAMPLE.com"), Ok(()));
+            assert_eq!(verify(&valid, "a.example.org"), Ok(()));
+            assert_eq!(verify(&valid, "127.0.0.1"), Ok(()));
+            assert_eq!(
+                verify(&valid, "a.b.example.org"),
+                err(CertificateError::NotValidForName)
+            );
+            assert_eq!(
+                verify(&valid, "example.net"),
+                err(CertificateError::NotValidForName)
+            );
+            assert_eq!(
+                verify(&valid, "127.0.0.2"),
+                err(CertificateError::NotValidForName)
+            );
+
+            let expired = cert(&["example.com"], past, rcgen::date_time_ymd(2001, 1, 1));
+            assert_eq!(
+                verify(&expired, "example.com"),
+                err(CertificateError::Expired)
+            );
+            let early = cert(&["example.com"], rcgen::date_time_ymd(2999, 1, 1), future);
+            assert_eq!(
+                verify(&early, "example.com"),
+                err(CertificateError::NotValidYet)
+            );
+        }
+
+        #[test]
+        fn certificate_expiry() {
+            let mut params =
+                rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.not_after = rcgen::date_time_ymd(2030, 1, 2);
+            let cert = params
+                .self_signed(&rcgen::KeyPair::generate().unwrap())
+                .unwrap();
+            assert_eq!(
+                expiry(cert.der()),
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_893_542_400))
+            );
+            assert_eq!(expiry(&CertificateDer::from(vec![0u8; 16])), None);
+        }
+
+        #[cfg(feature = "rcgen")]
+        #[test]
+        fn generated_identity() {
+            let (cert, key) = generate_self_signed("alice").unwrap();
+            assert!(expiry(&cert).is_some());
+            let (_, parsed) = x509_parser::parse_x509_certificate(&cert).unwrap();
+            assert_eq!(parsed.subject().to_string(), "CN=alice");
+            assert!(
+                rustls::ClientConfig::builder()
+                    .with_root_certificates(rustls::RootCertStore::empty())
+                    .with_client_auth_cert(vec![cert.clone()], key.clone_key())
+                    .is_ok()
+            );
+            assert!(crate::Client::with_identity(vec![cert], key).is_ok());
+        }
+    }
+}
+
+/// Trust-on-first-use certificate pinning, the usual way of authenticating
+/// Gemini servers.
+///
+/// The first time a host is visited the SHA-256 fingerprint of its certificate
+/// is remembered; after that, the host must keep presenting the same
+/// certificate or the connection is refused.
+pub mod tofu {
+    use rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use rustls::crypto::WebPkiSupportedAlgorithms;
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{DigitallySignedStruct, SignatureScheme};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// The SHA-256 digest of a DER encoded certificate.
+    pub type Fingerprint = [u8; 32];
+
+    /// Computes the [`Fingerprint`] of `cert`.
+    pub fn fingerprint(cert: &CertificateDer) -> Fingerprint {
+        Sha256::digest(cert).into()
+    }
+
+    /// Where a [`TofuVerifier`] keeps the fingerprints it has pinned.
+    ///
+    /// Implement this to persist pins across runs, e.g. in a `known_hosts`
+    /// style file.
+    pub trait TofuStore: std::fmt::Debug + Send + Sync {
+        /// The fingerprint pinned for `host`, if it has been seen before.
+        fn get(&self, host: &str) -> Option<Fingerprint>;
+        /// Pins `fingerprint` for `host` unless it already has a pin, and
+        /// returns the pin that's now in place.
+        ///
+        /// Two first connections to a host can race between
+        /// [`get`](Self::get) and `insert`, so this must never replace an
+        /// existing pin: the loser gets the winner's pin back and is checked
+        /// against it.
+        fn insert(
+            &self,
+            host: &str,
+            fingerprint: Fingerprint,
+        ) -> std::io::Result<Fingerprint>;
+    }
+
+    /// A [`TofuStore`] that forgets everything when dropped.
+    #[derive(Debug, Default)]
+    pub struct MemoryStore {
+        pins: Mutex<HashMap<String, Fingerprint>>,
+    }
+
+    impl MemoryStore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl TofuStore for MemoryStore {
+        fn get(&self, host: &str) -> Option<Fingerprint> {
+            self.pins.lock().unwrap().get(host).copied()
+        }
+
+        fn insert(
+            &self,
+            host: &str,
+            fingerprint: Fingerprint,
+        ) -> std::io::Result<Fingerprint> {
+            Ok(*self
+                .pins
+                .lock()
+                .unwrap()
+                .entry(host.to_string())
+                .or_insert(fingerprint))
+        }
+    }
+
+    /// A certificate verifier that pins each host's certificate on first use.
+    ///
+    /// Install it with [`Client::with_verifier`](crate::Client::with_verifier).
+    /// Handshake signatures are still checked, so a server can't present a
+    /// pinned certificate without holding its private key.
+    #[derive(Debug)]
+    pub struct TofuVerifier {
+        store: Arc<dyn TofuStore>,
+        algorithms: WebPkiSupportedAlgorithms,
+    }
+
+    impl TofuVerifier {
+        pub fn new(store: Arc<dyn TofuStore>) -> Self {
+            Self {
+                store,
+                algorithms: crate::crypto_provider().signature_verification_algorithms,
+            }
+        }
+
+        pub fn store(&self) -> &Arc<dyn TofuStore> {
+            &self.store
+        }
+    }
+
+    impl ServerCertVerifier for TofuVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let host = server_name.to_str();
+            let fingerprint = fingerprint(end_entity);
+            let pinned = match self.store.get(&host) {
+                Some(pinned) => pinned,
+                None => self
+                    .store
+                    .insert(&host, fingerprint)
+                    .map_err(|e| rustls::Error::General(e.to_string()))?,
+            };
+            if pinned != fingerprint {
+                return Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn memory_store_pins() {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .unwrap()
+                .cert;
+            let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .unwrap()
+                .cert;
+            let verifier = TofuVerifier::new(Arc::new(MemoryStore::new()));
+            let verify = |cert: &CertificateDer, host: &'static str| {
+                verifier.verify_server_cert(
+                    cert,
+                    &[],
+                    &ServerName::try_from(host).unwrap(),
+                    &[],
+                    UnixTime::now(),
+                )
+            };
+
+            assert!(verify(cert.der(), "localhost").is_ok());
+            assert_eq!(
+                verifier.store().get("localhost"),
+                Some(fingerprint(cert.der()))
+            );
+            assert!(verify(cert.der(), "localhost").is_ok());
+            assert!(verify(other.der(), "localhost").is_err());
+            // Pins are per host.
+            assert!(verify(other.der(), "example.com").is_ok());
+        }
+
+        #[test]
+        fn memory_store_keeps_first_pin() {
+            let store = MemoryStore::new();
+            assert_eq!(store.insert("localhost", [1; 32]).unwrap(), [1; 32]);
+            // A racing first connection gets the existing pin back.
+            assert_eq!(store.insert("localhost", [2; 32]).unwrap(), [1; 32]);
+            assert_eq!(store.get("localhost"), Some([1; 32]));
+        }
+
+        /// A store whose `get` always misses, as if every handshake were racing
+        /// another one to pin the host first.
+        #[derive(Debug, Default)]
+        struct RacingStore(MemoryStore);
+
+        impl TofuStore for RacingStore {
+            fn get(&self, _host: &str) -> Option<Fingerprint> {
+                None
+            }
+
+            fn insert(
+                &self,
+                host: &str,
+                fingerprint: Fingerprint,
+            ) -> std::io::Result<Fingerprint> {
+                self.0.insert(host, fingerprint)
+            }
+        }
+
+        #[test]
+        fn verifier_checks_the_stored_pin() {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .unwrap()
+                .cert;
+            let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                .unwrap()
+                .cert;
+            let verifier = TofuVerifier::new(Arc::new(RacingStore::default()));
+            let verify = |cert: &CertificateDer| {
+                verifier.verify_server_cert(
+                    cert,
+                    &[],
+                    &ServerName::try_from("localhost").unwrap(),
+                    &[],
+                    UnixTime::now(),
+                )
+            };
+            assert!(verify(cert.der()).is_ok());
+            assert!(verify(other.der()).is_err());
+            assert!(verify(cert.der()).is_ok());
+        }
+    }
+}
+
+/// A minimal Gemini server, the other end of [`Client`].
+pub mod server {
+    use crate::status::{PermanentFailure, Status};
+    use crate::{Request, RequestError, ResponseHeader};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use std::io::{self, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    type TlsStream = rustls::StreamOwned<rustls::ServerConnection, TcpStream>;
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum ServerError {
+        #[error("I/O: {0}")]
+        Io(#[from] io::Error),
+        #[error("Rustls: {0}")]
+        Rustls(#[from] rustls::Error),
+        /// The client sent a request that couldn't be parsed. It was answered
+        /// with status 59 (bad request).
+        #[error("Request: {0}")]
+        Request(#[from] RequestError),
+    }
+
+    /// Listens for connections and answers each request with the header and
+    /// body a handler returns for it. Requests that can't be parsed are
+    /// answered with status 59 (bad request) without calling the handler.
+    #[derive(Debug)]
+    pub struct Server {
+        listener: TcpListener,
+        config: Arc<rustls::ServerConfig>,
+        timeout: Option<Duration>,
+        max_connections: Option<usize>,
+    }
+
+    impl Server {
+        /// Listens on `addr`, identifying as the certificate chain `certs`
+        /// (end-entity first) with the private key `key`.
+        pub fn bind(
+            addr: impl ToSocketAddrs,
+            certs: Vec<CertificateDer<'static>>,
+            key: PrivateKeyDer<'static>,
+        ) -> Result<Self, ServerError> {
+            let config = rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)?;
+            Ok(Self {
+                listener: TcpListener::bind(addr)?,
+                config: Arc::new(config),
+                timeout: None,
+                max_connections: None,
+            })
+        }
+
+        /// Give up on a connection that hasn't completed the handshake and sent
+        /// its request within `timeout`, so that a client that goes quiet, or
+        /// trickles in a byte at a time, can't hold on to a thread forever.
+        /// Writing the response then gives up if a single write blocks for
+        /// longer than `timeout`. By default there's no limit.
+        pub fn with_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+
+        /// Handle at most `max` connections at once in [`serve`](Self::serve),
+        /// leaving further ones waiting to be accepted until one finishes. A
+        /// `max` of `0` is taken as `1`. By default there's no limit, so every
+        /// connection gets a thread of its own.
+        pub fn with_max_connections(mut self, max: usize) -> Self {
+            self.max_connections = Some(max.max(1));
+            self
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.listener.local_addr()
+        }
+
+        /// Accepts connections forever, handling each on its own thread.
+        ///
+        /// Only fails if accepting a connection does. Failures of a single
+        /// connection, such as a client hanging up halfway through, are
+        /// dropped.
+        pub fn serve<H>(&self, handler: H) -> Result<(), ServerError>
+        where
+            H: Fn(Request) -> (ResponseHeader, Vec<u8>) + Send + Sync + 'static,
+        {
+            let handler = Arc::new(handler);
+            let slots = Arc::new(Slots::default());
+            loop {
+                if let Some(max) = self.max_connections {
+                    slots.acquire(max);
+                }
+                let (sock, _) = match self.listener.accept() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        slots.release();
+                        return Err(e.into());
+                    }
+                };
+                let (config, timeout) = (self.config.clone(), self.timeout);
+                let (handler, slots) = (handler.clone(), slots.clone());
+                std::thread::spawn(move || {
+                    let _ = handle(sock, config, timeout, &*handler);
+                    slots.release();
+                });
+            }
+        }
+
+        /// Accepts a single connection and answers it on this thread.
+        pub fn serve_one<H>(&self, handler: H) -> Result<(), ServerError>
+        where
+            H: Fn(Request) -> (ResponseHeader, Vec<u8>),
+        {
+            let (sock, _) = self.listener.accept()?;
+            handle(sock, self.config.clone(), self.timeout, &handler)
+        }
+    }
+
+    /// Counts the connections [`Server::serve`] is handling.
+    #[derive(Debug, Default)]
+    struct Slots {
+        active: Mutex<usize>,
+        freed: Condvar,
+    }
+
+    impl Slots {
+        /// Waits until fewer than `max` connections are active and takes a slot.
+        fn acquire(&self, max: usize) {
+            let mut active = self.active.lock().unwrap();
+            while *active >= max {
+                active = self.freed.wait(active).unwrap();
+            }
+            *active += 1;
+        }
+
+        fn release(&self) {
+            let mut active = self.active.lock().unwrap();
+            *active = active.saturating_sub(1);
+            self.freed.notify_one();
+        }
+    }
+
+    /// Reads off the connection, bounding each read and write by the time left
+    /// until `deadline`, which bounds all of them together.
+    struct Deadline<'a> {
+        stream: &'a mut TlsStream,
+        deadline: Option<Instant>,
+    }
+
+    impl Deadline<'_> {
+        fn bound(&self) -> io::Result<()> {
+            let Some(deadline) = self.deadline else {
+                return Ok(());
+            };
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.sock.set_read_timeout(Some(left))?;
+            self.stream.sock.set_write_timeout(Some(left))
+        }
+
+        fn handshake(&mut self) -> io::Result<()> {
+            while self.stream.conn.is_handshaking() {
+                self.bound()?;
+                self.stream.conn.complete_io(&mut self.stream.sock)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl io::Read for Deadline<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.bound()?;
+            self.stream.read(buf)
+        }
+    }
+
+    fn handle(
+        sock: TcpStream,
+        config: Arc<rustls::ServerConfig>,
+        timeout: Option<Duration>,
+        handler: &dyn Fn(Request) -> (ResponseHeader, Vec<u8>),
+    ) -> Result<(), ServerError> {
+        let conn = rustls::ServerConnection::new(config)?;
+        let mut stream = rustls::StreamOwned::new(conn, sock);
+        let mut reader = Deadline {
+            stream: &mut stream,
+            deadline: timeout.map(|x| Instant::now() + x),
+        };
+        reader.handshake()?;
+        let request = Request::read(&mut reader);
+        stream.sock.set_read_timeout(timeout)?;
+        stream.sock.set_write_timeout(timeout)?;
+        let result = match request {
+            Ok(request) => {
+                let (header, body) = handler(request);
+                header.write(&mut stream)?;
+                stream.write_all(&body)?;
+                Ok(())
+            }
+            Err(RequestError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                let status = Status::PermanentFailure(PermanentFailure::BadRequest);
+                ResponseHeader::new(status, status.default_meta())
+                    .expect("default META is valid")
+                    .write(&mut stream)?;
+                Err(e.into())
+            }
+        };
+        stream.conn.send_close_notify();
+        stream.flush()?;
+        result
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::Client;
+
+        fn server() -> Server {
+            let rcgen::CertifiedKey { cert, key_pair } =
+                rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+                    .unwrap();
+            let key =
+                rustls::pki_types::PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+            Server::bind("127.0.0.1:0", vec![cert.der().clone()], key.into())
+                .unwrap()
+                .with_timeout(Duration::from_secs(5))
+        }
+
+        #[test]
+        fn serves_requests() {
+            let server = server();
+            let addr = server.local_addr().unwrap();
+            let thread = std::thread::spawn(move || {
+                server.serve_one(|request| {
+                    let header =
+                        ResponseHeader::new(Status::Success, "text/plain").unwrap();
+                    (
+                        header,
+                        request.url().path.unwrap_or_default().as_bytes().to_vec(),
+                    )
+                })
+            });
+            let response = Client::new()
+                .send_request_to(addr, Request::new("gemini://localhost/hello").unwrap())
+                .unwrap();
+            assert_eq!(response.header.status, Status::Success);
+            assert_eq!(response.header.meta(), "text/plain");
+            assert_eq!(response.body, b"/hello");
+            thread.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn times_out_slow_requests() {
+            use rustls::pki_types::ServerName;
+            let server = server().with_timeout(Duration::from_millis(300));
+            let addr = server.local_addr().unwrap();
+            let thread = std::thread::spawn(move || {
+                let start = Instant::now();
+                let result = server.serve_one(|_| unreachable!("the request is cut off"));
+                (result, start.elapsed())
+            });
+            let config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(crate::DummyVerifier))
+                .with_no_client_auth();
+            let conn = rustls::ClientConnection::new(
+                Arc::new(config),
+                ServerName::try_from("localhost").unwrap(),
+            )
+            .unwrap();
+            let mut stream =
+                rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+            // Each byte comes well within the timeout, but the request as a
+            // whole doesn't.
+            for byte in b"gemini://localhost/" {
+                if stream
+                    .write_all(&[*byte])
+                    .and_then(|_| stream.flush())
+                    .is_err()
+                {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            let (result, elapsed) = thread.join().unwrap();
+            assert!(matches!(result, Err(ServerError::Io(_))), "{result:?}");
+            assert!(elapsed < Duration::from_millis(700), "{elapsed:?}");
+        }
+
+        #[test]
+        fn limits_connections() {
+            let server = server()
+                .with_timeout(Duration::from_millis(300))
+                .with_max_connections(1);
+            let addr = server.local_addr().unwrap();
+            std::thread::spawn(move || {
+                server.serve(|_| {
+                    let header =
+                        ResponseHeader::new(Status::Success, "text/plain").unwrap();
+                    (header, b"ok".to_vec())
+                })
+            });
+            // An idle connection takes the only slot until it times out.
+            let _idle = TcpStream::connect(addr).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            let start = Instant::now();
+            let response = Client::new()
+                .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+                .unwrap();
+            assert_eq!(response.body, b"ok");
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        }
+
+        #[test]
+        fn answers_bad_requests() {
+            use rustls::pki_types::ServerName;
+            let server = server();
+            let addr = server.local_addr().unwrap();
+            let thread = std::thread::spawn(move || {
+                server.serve_one(|_| unreachable!("the request is invalid"))
+            });
+            let config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(crate::DummyVerifier))
+                .with_no_client_auth();
+            let conn = rustls::ClientConnection::new(
+                Arc::new(config),
+                ServerName::try_from("localhost").unwrap(),
+            )
+            .unwrap();
+            let mut stream =
+                rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+            stream.write_all(b"https://example.com/\r\n").unwrap();
+            stream.flush().unwrap();
+            let response = crate::Response::read(&mut stream).unwrap();
+            assert_eq!(
+                response.header.status,
+                Status::PermanentFailure(PermanentFailure::BadRequest)
+            );
+            assert!(matches!(
+                thread.join().unwrap(),
+                Err(ServerError::Request(RequestError::NotGemini(
+                    crate::uri::GeminiUriError::WrongScheme
+                )))
+            ));
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("I/O: {0}")]
+    Io(std::io::Error),
+    /// Connecting to the server or waiting for data from it took longer than
+    /// the client's timeout allows. Worth retrying later.
+    #[error("Timed out")]
+    Timeout,
+    #[error("Response: {0}")]
+    Response(#[from] ResponseReadError),
+    #[error("Rustls: {0}")]
+    Rustls(#[from] rustls::Error),
+    /// The TLS handshake failed, e.g. the server's certificate was rejected or
+    /// there was no protocol version in common. No request was sent.
+    #[error("TLS handshake: {0}")]
+    Handshake(rustls::Error),
+    #[error("Port is invalid")]
+    BadPort,
+    #[error("Gave up after following {0} redirects")]
+    TooManyRedirects(usize),
+    /// A redirect pointed back at a URL that was already requested.
+    #[error("Redirect loop at {0}")]
+    RedirectLoop(String),
+    #[error("Host is not a valid server name")]
+    BadHost,
+    #[error("Request: {0}")]
+    Request(#[from] RequestError),
+    #[error("URI: {0}")]
+    Uri(#[from] uri::Error),
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            // A read timeout shows up as `WouldBlock` on Unix.
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+/// A Gemini client.
+///
+/// Cloning a client is cheap, as the TLS configuration is reference counted,
+/// so a client can be cloned into each worker thread. Clones share the TLS
+/// session cache and the [`handshake_stats`](Self::handshake_stats).
+#[derive(Clone)]
+pub struct Client {
+    cfg: Arc<rustls::client::ClientConfig>,
+    stats: Arc<Stats>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_body_size: usize,
+    read_buffer_size: usize,
+    lenient_headers: bool,
+}
+
+/// Counts of the TLS handshakes a [`Client`] has performed.
+///
+/// Gemini opens a new connection for every request, but rustls remembers
+/// sessions per server name so that later handshakes with the same server can
+/// be resumed, which is much cheaper for e.g. a crawler.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeStats {
+    pub handshakes: usize,
+    /// How many of the handshakes resumed a previous session.
+    pub resumed: usize,
+}
+
+impl HandshakeStats {
+    /// The fraction of handshakes that were resumed, from `0.0` to `1.0`.
+    pub fn resumption_rate(&self) -> f64 {
+        if self.handshakes == 0 {
+            return 0.0;
+        }
+        self.resumed as f64 / self.handshakes as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    handshakes: std::sync::atomic::AtomicUsize,
+    resumed: std::sync::atomic::AtomicUsize,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures a [`Client`].
+///
+/// By default the client accepts any server certificate, as Gemini capsules
+/// overwhelmingly use self-signed certificates.
+#[derive(Debug, Default)]
+pub struct ClientBuilder {
+    roots: Option<rustls::RootCertStore>,
+    verifier: Option<Arc<dyn ServerCertVerifier>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+    read_buffer_size: Option<usize>,
+    session_cache: Option<usize>,
+    lenient_headers: bool,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only trust servers whose certificates chain up to one of `roots`, e.g.
+    /// an organisation's private CA, rather than accepting any certificate.
+    pub fn with_root_store(mut self, roots: rustls::RootCertStore) -> Self {
+        self.roots = Some(roots);
+        self
+    }
+
+    /// Verify server certificates with `verifier`, e.g. a
+    /// [`TofuVerifier`](tofu::TofuVerifier). This takes precedence over
+    /// [`with_root_store`](Self::with_root_store).
+    pub fn with_verifier(mut self, verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Present the client certificate `certs` (end-entity first) to servers
+    /// that ask for one, for capsules answering with status 60.
+    pub fn with_identity(
+        mut self,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.identity = Some((certs, key));
+        self
+    }
+
+    /// Give up on connecting to a server after `timeout`. By default this is
+    /// left to the operating system, which can take minutes.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a connection if a single read from the server blocks for
+    /// longer than `timeout`. By default reads wait forever.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a response if no bytes of its body arrive for `timeout`,
+    /// even though the server keeps the connection open, e.g. because it
+    /// stalled halfway through. Unlike [`read_timeout`](Self::read_timeout),
+    /// which bounds every read, this only applies once the header is in.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on the TLS handshake if it hasn't completed within `timeout`,
+    /// e.g. because the server accepted the connection but never answers, or
+    /// answers a byte at a time so that no single read times out.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with [`ResponseReadError::BodyTooLarge`] on responses with a body
+    /// larger than `max` bytes. Defaults to
+    /// [`DEFAULT_MAX_BODY_SIZE`](Client::DEFAULT_MAX_BODY_SIZE).
+    pub fn max_body_size(mut self, max: usize) -> Self {
+        self.max_body_size = Some(max);
+        self
+    }
+
+    /// Remember up to `size` TLS sessions so that later connections to the
+    /// same servers can resume them with an abbreviated handshake, see
+    /// [`HandshakeStats`]. A size of `0` turns resumption off. By default
+    /// rustls's own in-memory cache is used, which holds 256 sessions.
+    ///
+    /// rustls keeps several TLS 1.3 tickets for each server, which count
+    /// towards `size`, so a cache that's meant to be useful should have room
+    /// for a few dozen.
+    pub fn session_cache(mut self, size: usize) -> Self {
+        self.session_cache = Some(size);
+        self
+    }
+
+    /// Read responses from the connection `size` bytes at a time. Defaults to
+    /// [`DEFAULT_READ_BUFFER_SIZE`](Client::DEFAULT_READ_BUFFER_SIZE); a size
+    /// of `0` is taken as `1`.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = Some(size);
+        self
+    }
+
+    /// Accept response headers whose META contains control characters, which
+    /// are otherwise rejected, see [`ResponseHeader::parse_lenient`].
+    pub fn lenient_headers(mut self, lenient: bool) -> Self {
+        self.lenient_headers = lenient;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, ClientError> {
+        let config = rustls::ClientConfig::builder();
+        let config = match (self.verifier, self.roots) {
+            (Some(verifier), _) => config
+                .dangerous()
+                .with_custom_certificate_verifier(verifier),
+            (None, Some(roots)) => config.with_root_certificates(roots),
+            (None, None) => config
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(DummyVerifier)),
+        };
+        let mut config = match self.identity {
+            Some((certs, key)) => config.with_client_auth_cert(certs, key)?,
+            None => config.with_no_client_auth(),
+        };
+        match self.session_cache {
+            Some(0) => config.resumption = rustls::client::Resumption::disabled(),
+            Some(size) => {
+                config.resumption = rustls::client::Resumption::in_memory_sessions(size)
+            }
+            None => {}
+        }
+        Ok(Client {
+            cfg: Arc::new(config),
+            stats: Arc::default(),
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            idle_timeout: self.idle_timeout,
+            handshake_timeout: self.handshake_timeout,
+            max_body_size: self.max_body_size.unwrap_or(Client::DEFAULT_MAX_BODY_SIZE),
+            read_buffer_size: self
+                .read_buffer_size
+                .unwrap_or(Client::DEFAULT_READ_BUFFER_SIZE)
+                .max(1),
+            lenient_headers: self.lenient_headers,
+        })
+    }
+}
+
+impl Client {
+    /// The largest response body a client accepts unless configured otherwise
+    /// with [`ClientBuilder::max_body_size`].
+    pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+    /// How many bytes of a response are read at a time unless configured
+    /// otherwise with [`ClientBuilder::read_buffer_size`].
+    pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+    pub fn new() -> Self {
+        ClientBuilder::new()
+            .build()
+            .expect("the default configuration is valid")
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// A client that checks server certificates with `verifier` instead of
+    /// accepting all of them.
+    pub fn with_verifier(verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        ClientBuilder::new()
+            .with_verifier(verifier)
+            .build()
+            .expect("a custom verifier is a valid configuration")
+    }
+
+    /// A client that presents the client certificate `certs` (end-entity
+    /// first) to every server that asks for one. Fails if `key` can't be used
+    /// with the certificate.
+    pub fn with_identity(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, ClientError> {
+        ClientBuilder::new().with_identity(certs, key).build()
+    }
+
+    /// A client that uses `timeout` both for connecting and for each read,
+    /// see [`ClientBuilder::connect_timeout`] and
+    /// [`ClientBuilder::read_timeout`].
+    pub fn with_timeout(timeout: Duration) -> Self {
+        ClientBuilder::new()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .build()
+            .expect("the default configuration is valid")
+    }
+
+    /// A client that accepts any server certificate that's currently valid
+    /// and for the host being connected to, whoever issued it, see
+    /// [`cert::ValidityVerifier`].
+    pub fn with_expiry_checks() -> Self {
+        Self::with_verifier(Arc::new(cert::ValidityVerifier::new()))
+    }
+
+    /// A client that remembers up to `size` TLS sessions for resuming, see
+    /// [`ClientBuilder::session_cache`].
+    pub fn with_session_cache(size: usize) -> Self {
+        ClientBuilder::new()
+            .session_cache(size)
+            .build()
+            .expect("the default configuration is valid")
+    }
+
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        use std::sync::atomic::Ordering;
+        HandshakeStats {
+            handshakes: self.stats.handshakes.load(Ordering::Relaxed),
+            resumed: self.stats.resumed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn send_request(&self, r: Request) -> Result<Response, ClientError> {
+        let url = r.url();
+        let host = url.host.ok_or(ClientError::BadHost)?;
+        let port = url.port_or_default().map_err(|_| ClientError::BadPort)?;
+        let mut conn = self.connect(host, port)?;
+        conn.send(r)?;
+        conn.read_response()
+    }
+
+    /// Uploads `body` to the URL of `req` with the Titan protocol, reading
+    /// back the server's response like for a Gemini request. Titan servers
+    /// listen on Gemini's port 1965 unless the URL says otherwise.
+    pub fn send_titan(
+        &self,
+        req: TitanRequest,
+        body: &[u8],
+    ) -> Result<Response, ClientError> {
+        let url = req.url();
+        let host = url.host.ok_or(ClientError::BadHost)?;
+        let port = url.port_or_default().map_err(|_| ClientError::BadPort)?;
+        let mut conn = self.connect(host, port)?;
+        conn.send_titan(&req, body)?;
+        conn.read_response()
+    }
+
+    /// Like [`send_request`](Self::send_request), but follows up to
+    /// `max_redirects` redirects, resolving each target against the URL that
+    /// was redirected from. The final response is returned whatever its
+    /// status, with the URL it came from in [`Response::url`].
+    ///
+    /// Fails with [`ClientError::TooManyRedirects`] if there are more
+    /// redirects than that, or with [`ClientError::RedirectLoop`] as soon as a
+    /// URL comes up a second time.
+    pub fn send_request_following(
+        &self,
+        r: Request,
+        max_redirects: usize,
+    ) -> Result<Response, ClientError> {
+        let mut request = r;
+        let mut visited = std::collections::HashSet::new();
+        loop {
+            let url = uri::UriOwned::from(request.url());
+            visited.insert(url.clone());
+            let response = self.send_request(request)?;
+            if !response.header.status.is_redirect() {
+                return Ok(response);
+            }
+            if visited.len() > max_redirects {
+                return Err(ClientError::TooManyRedirects(max_redirects));
+            }
+            let mut target =
+                uri::Uri::new(response.header.meta())?.resolve(&url.as_ref());
+            // Fragments are never sent to the server.
+            target.fragment = None;
+            if visited.contains(&target) {
+                return Err(ClientError::RedirectLoop(target.to_string()));
+            }
+            request = Request::try_from(&target)?;
+        }
+    }
+
+    /// Fetches `url` the way a browser would, following up to five redirects,
+    /// and sorts the response into what to show the user.
+    pub fn fetch_document(&self, url: &str) -> Result<FetchResult, ClientError> {
+        let response = self.send_request_following(Request::new(url)?, 5)?;
+        let header = response.header;
+        Ok(match header.status {
+            Status::Input(input) => FetchResult::Input {
+                prompt: header.meta().to_string(),
+                sensitive: input == status::Input::Sensitive,
+            },
+            Status::Success if response.is_gemtext() => {
+                match String::from_utf8(response.body) {
+                    Ok(body) => FetchResult::Document(gemtext::Document::new(&body)),
+                    Err(e) => FetchResult::Blob {
+                        mime: header.meta().to_string(),
+                        body: e.into_bytes(),
+                    },
+                }
+            }
+            Status::Success => FetchResult::Blob {
+                mime: header.meta().to_string(),
+                body: response.body,
+            },
+            status => FetchResult::Failure {
+                status,
+                meta: header.meta().to_string(),
+            },
+        })
+    }
+
+    /// Like [`send_request`](Self::send_request), but presents `identity`
+    /// instead of the client's own certificate, if any, so that different
+    /// capsules can be shown different certificates.
+    pub fn send_request_as(
+        &self,
+        r: Request,
+        identity: &Identity,
+    ) -> Result<Response, ClientError> {
+        let mut cfg = (*self.cfg).clone();
+        cfg.client_auth_cert_resolver = Arc::new(identity.clone());
+        // A resumed session would carry over whichever certificate was
+        // presented when it was established.
+        cfg.resumption = rustls::client::Resumption::disabled();
+        Client {
+            cfg: Arc::new(cfg),
+            stats: self.stats.clone(),
+            ..*self
+        }
+        .send_request(r)
+    }
+
+    /// Opens a TLS connection to `host` on `port` without sending anything,
+    /// for callers that want to drive the exchange themselves.
+    pub fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<GeminiConnection, ClientError> {
+        use std::net::ToSocketAddrs;
+        let host = unbracket(host);
+        let Some(timeout) = self.connect_timeout else {
+            return self.open(std::net::TcpStream::connect((host, port))?, host);
+        };
+        let mut last_err = None;
+        for addr in (host, port).to_socket_addrs()? {
+            match std::net::TcpStream::connect_timeout(&addr, timeout) {
+                Ok(sock) => return self.open(sock, host),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::other("host resolved to no addresses"))
+            .into())
+    }
+
+    /// Sends `r` to the server at `addr` rather than the host in its URL,
+    /// bypassing DNS, e.g. to try out a staging server. The request line and
+    /// the TLS server name still come from `r`'s URL.
+    pub fn send_request_to(
+        &self,
+        addr: std::net::SocketAddr,
+        r: Request,
+    ) -> Result<Response, ClientError> {
+        let host = r.url().host.ok_or(ClientError::BadHost)?;
+        self.send_request_with_sni(addr, r, host)
+    }
+
+    /// Like [`send_request_to`](Self::send_request_to), but sends `sni` as the
+    /// TLS server name, which the server's certificate is checked against,
+    /// instead of the host in `r`'s URL. This allows e.g. connecting to a
+    /// capsule by IP address before its DNS record points there.
+    pub fn send_request_with_sni(
+        &self,
+        addr: std::net::SocketAddr,
+        r: Request,
+        sni: &str,
+    ) -> Result<Response, ClientError> {
+        // Don't bother connecting with a name that can't be sent.
+        ServerName::try_from(sni).map_err(|_| ClientError::BadHost)?;
+        let sock = match self.connect_timeout {
+            Some(timeout) => std::net::TcpStream::connect_timeout(&addr, timeout)?,
+            None => std::net::TcpStream::connect(addr)?,
+        };
+        let mut conn = self.open(sock, sni)?;
+        conn.send(r)?;
+        conn.read_response()
+    }
+
+    /// Connects to a server and completes the TLS handshake without sending a
+    /// request, reporting what was negotiated, e.g. for a capsule operator
+    /// checking their setup.
+    ///
+    /// The certificate is checked by this client's verifier like for any other
+    /// connection, so a server it would refuse fails with
+    /// [`ClientError::Handshake`].
+    pub fn probe(&self, host: &str, port: u16) -> Result<ServerProbe, ClientError> {
+        let mut conn = self.connect(host, port)?;
+        conn.handshake()?;
+        let tls = &mut conn.stream.conn;
+        let probe = ServerProbe {
+            version: tls
+                .protocol_version()
+                .ok_or(ClientError::Rustls(rustls::Error::HandshakeNotComplete))?,
+            cipher_suite: tls
+                .negotiated_cipher_suite()
+                .ok_or(ClientError::Rustls(rustls::Error::HandshakeNotComplete))?
+                .suite(),
+            certificates: tls
+                .peer_certificates()
+                .unwrap_or_default()
+                .iter()
+                .map(|cert| cert.clone().into_owned())
+                .collect(),
+        };
+        tls.send_close_notify();
+        // The server may well have hung up already, so this is a courtesy.
+        let _ = tls.complete_io(&mut conn.stream.sock);
+        Ok(probe)
+    }
+
+    fn open(
+        &self,
+        sock: std::net::TcpStream,
+        host: &str,
+    ) -> Result<GeminiConnection, ClientError> {
+        let server_name = ServerName::try_from(unbracket(host))
+            .map_err(|_| ClientError::BadHost)?
+            .to_owned();
+        sock.set_read_timeout(self.read_timeout)?;
+        let cc = rustls::ClientConnection::new(self.cfg.clone(), server_name)?;
+        Ok(GeminiConnection {
+            stream: rustls::StreamOwned::new(cc, sock),
+            stats: self.stats.clone(),
+            idle_timeout: self.idle_timeout,
+            handshake_timeout: self.handshake_timeout,
+            max_body_size: self.max_body_size,
+            read_buffer_size: self.read_buffer_size,
+            lenient_headers: self.lenient_headers,
+            url: None,
+            truncated: false,
+        })
+    }
+}
+
+/// What [`Client::fetch_document`] got back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchResult {
+    /// A `text/gemini` page.
+    Document(gemtext::Document),
+    /// The server wants the user to answer `prompt` and request the URL again
+    /// with the answer as the query, see [`Request::from_input`].
+    Input { prompt: String, sensitive: bool },
+    /// Any other successful response, with its media type.
+    Blob { mime: String, body: Vec<u8> },
+    /// A failure or client certificate status, with the server's META.
+    Failure { status: Status, meta: String },
+}
+
+/// What [`Client::probe`] found out about a server's TLS setup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerProbe {
+    pub version: rustls::ProtocolVersion,
+    pub cipher_suite: rustls::CipherSuite,
+    /// The certificate chain the server sent, its own certificate first.
+    pub certificates: Vec<CertificateDer<'static>>,
+}
+
+impl ServerProbe {
+    /// The subject of the server's certificate, e.g. `CN=example.com`.
+    pub fn subject(&self) -> Option<String> {
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(self.certificates.first()?).ok()?;
+        Some(cert.subject().to_string())
+    }
+
+    /// When the server's certificate expires, see [`cert::expiry`].
+    pub fn expiry(&self) -> Option<std::time::SystemTime> {
+        cert::expiry(self.certificates.first()?)
+    }
+
+    /// The server's certificate's fingerprint, as pinned by
+    /// [`tofu::TofuVerifier`].
+    pub fn fingerprint(&self) -> Option<tofu::Fingerprint> {
+        self.certificates.first().map(tofu::fingerprint)
+    }
+}
+
+/// Strips the brackets off an IPv6 literal host from a URL, e.g. `[::1]`, to
+/// get an address that can be resolved and used as a server name.
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// rustls reports TLS failures, such as a fatal alert from the server, as I/O
+/// errors wrapping the [`rustls::Error`].
+fn tls_error(e: &io::Error) -> Option<rustls::Error> {
+    e.get_ref()?.downcast_ref::<rustls::Error>().cloned()
+}
+
+/// A client certificate and its private key, for picking the certificate per
+/// request with [`Client::send_request_as`].
+#[derive(Debug, Clone)]
+pub struct Identity {
+    key: Arc<rustls::sign::CertifiedKey>,
+}
+
+impl Identity {
+    /// Fails if `key` can't be parsed or doesn't belong to the end-entity
+    /// certificate, which comes first in `certs`.
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, ClientError> {
+        let key = rustls::sign::CertifiedKey::from_der(certs, key, &crypto_provider())?;
+        Ok(Self { key: Arc::new(key) })
+    }
+}
+
+/// The crypto provider [`ClientConfig::builder`](rustls::ClientConfig::builder)
+/// picks, the process default, so that verifiers and keys agree with the
+/// configs they end up in.
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    rustls::ClientConfig::builder().crypto_provider().clone()
+}
+
+impl rustls::client::ResolvesClientCert for Identity {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.key.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+type TlsStream = rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream>;
+
+/// A live TLS connection to a Gemini server, returned by [`Client::connect`].
+///
+/// Gemini allows exactly one request per connection: the server closes it
+/// after sending the response, so a `GeminiConnection` is spent after a single
+/// [`send`](Self::send) and [`read_response`](Self::read_response).
+pub struct GeminiConnection {
+    stream: TlsStream,
+    stats: Arc<Stats>,
+    idle_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_body_size: usize,
+    read_buffer_size: usize,
+    lenient_headers: bool,
+    /// What was sent, for [`Response::url`].
+    url: Option<uri::UriOwned>,
+    /// Whether the server hung up without a close_notify, for
+    /// [`Response::truncated`].
+    truncated: bool,
+}
+
+impl GeminiConnection {
+    /// Writes the request line and flushes it to the server, completing the
+    /// TLS handshake first if needed.
+    ///
+    /// A failed handshake is reported as [`ClientError::Handshake`].
+    pub fn send(&mut self, request: Request) -> Result<(), ClientError> {
+        self.url = Some(request.url().into());
+        self.send_with(|stream| request.write(stream))
+    }
+
+    /// Like [`send`](Self::send), but uploads `body` with the Titan protocol.
+    pub fn send_titan(
+        &mut self,
+        request: &TitanRequest,
+        body: &[u8],
+    ) -> Result<(), ClientError> {
+        let line = request.line(body.len())?;
+        self.url = Some(request.url().into());
+        self.send_with(|stream| {
+            io::Write::write_all(stream, line.as_bytes())?;
+            io::Write::write_all(stream, b"\r\n")?;
+            io::Write::write_all(stream, body)
+        })
+    }
+
+    fn send_with<E: Into<ClientError>>(
+        &mut self,
+        write: impl FnOnce(&mut TlsStream) -> Result<(), E>,
+    ) -> Result<(), ClientError> {
+        self.handshake()?;
+        write(&mut self.stream).map_err(Into::into)?;
+        io::Write::flush(&mut self.stream)?;
+        Ok(())
+    }
+
+    /// Completes the TLS handshake, counting it in the client's
+    /// [`HandshakeStats`] once it's done.
+    fn handshake(&mut self) -> Result<(), ClientError> {
+        use std::sync::atomic::Ordering;
+        if !self.stream.conn.is_handshaking() {
+            return Ok(());
+        }
+        match self.handshake_timeout {
+            None => self.complete_handshake(None)?,
+            Some(timeout) => {
+                let sock = &self.stream.sock;
+                let (read_timeout, write_timeout) =
+                    (sock.read_timeout()?, sock.write_timeout()?);
+                let result =
+                    self.complete_handshake(Some(std::time::Instant::now() + timeout));
+                self.stream.sock.set_read_timeout(read_timeout)?;
+                self.stream.sock.set_write_timeout(write_timeout)?;
+                result?;
+            }
+        }
+        self.stats.handshakes.fetch_add(1, Ordering::Relaxed);
+        if self.stream.conn.handshake_kind() == Some(rustls::HandshakeKind::Resumed) {
+            self.stats.resumed.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn complete_handshake(
+        &mut self,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<(), ClientError> {
+        while self.stream.conn.is_handshaking() {
+            if let Some(deadline) = deadline {
+                // Bounding each read and write by the time that's left bounds
+                // the handshake as a whole.
+                let left = deadline.saturating_duration_since(std::time::Instant::now());
+                if left.is_zero() {
+                    return Err(ClientError::Timeout);
+                }
+                let sock = &self.stream.sock;
+                sock.set_read_timeout(Some(
+                    sock.read_timeout()?.map_or(left, |x| x.min(left)),
+                ))?;
+                sock.set_write_timeout(Some(
+                    sock.write_timeout()?.map_or(left, |x| x.min(left)),
+                ))?;
+            }
+            if let Err(e) = self.stream.conn.complete_io(&mut self.stream.sock) {
+                return Err(match tls_error(&e) {
+                    Some(e) => ClientError::Handshake(e),
+                    None => e.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads until the server closes the connection and parses the response.
+    pub fn read_response(&mut self) -> Result<Response, ClientError> {
+        let read_timeout = self.stream.sock.read_timeout()?;
+        let result = self.read_all(read_timeout);
+        self.stream.sock.set_read_timeout(read_timeout)?;
+        let mut response = Response::read_with(
+            io::Cursor::new(result?),
+            self.max_body_size,
+            self.lenient_headers,
+        )?;
+        response.received_at = Some(std::time::SystemTime::now());
+        response.peer_certificate = self
+            .stream
+            .conn
+            .peer_certificates()
+            .and_then(<[_]>::first)
+            .map(|cert| cert.clone().into_owned());
+        response.url = self.url.clone();
+        response.truncated = self.truncated;
+        Ok(response)
+    }
+
+    /// Reads the raw response, enforcing the idle timeout between the bytes of
+    /// the body on top of the socket's own `read_timeout`.
+    fn read_all(
+        &mut self,
+        read_timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, ClientError> {
+        let limit = ResponseHeader::MAX_LEN.saturating_add(self.max_body_size);
+        let mut data = Vec::new();
+        let mut buf = vec![0; self.read_buffer_size];
+        // When the last bytes of the body arrived, from the end of the header on.
+        let mut last_progress: Option<std::time::Instant> = None;
+        while data.len() <= limit {
+            if let (Some(idle), Some(last)) = (self.idle_timeout, last_progress) {
+                let left = idle.saturating_sub(last.elapsed());
+                if left.is_zero() {
+                    return Err(ClientError::Timeout);
+                }
+                self.stream
+                    .sock
+                    .set_read_timeout(Some(read_timeout.map_or(left, |x| x.min(left))))?;
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    data.extend_from_slice(&buf[..n]);
+                    if last_progress.is_some() || data.contains(&b'\n') {
+                        last_progress = Some(std::time::Instant::now());
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Plenty of servers close the socket without sending a TLS
+                // close_notify first, so this isn't an error, but it's also
+                // how an attacker cutting the body short would look.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.truncated = true;
+                    break;
+                }
+                Err(e) => return Err(tls_error(&e).map_or(e.into(), ClientError::Rustls)),
+            }
+        }
+        Ok(data)
+    }
+}
+#[derive(Debug)]
+struct DummyVerifier;
+
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+impl ServerCertVerifier for DummyVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        use rustls::SignatureScheme::*;
+        vec![
+            RSA_PKCS1_SHA1,
+            ECDSA_SHA1_Legacy,
+            RSA_PKCS1_SHA256,
+            ECDSA_NISTP256_SHA256,
+            RSA_PKCS1_SHA384,
+            ECDSA_NISTP384_SHA384,
+            RSA_PKCS1_SHA512,
+            ECDSA_NISTP521_SHA512,
+            RSA_PSS_SHA256,
+            RSA_PSS_SHA384,
+            RSA_PSS_SHA512,
+            ED25519,
+            ED448,
+        ]
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::JoinHandle;
+
+    /// A server config presenting `cert`, signed with `key`.
+    fn server_config(
+        cert: &rcgen::Certificate,
+        key: &rcgen::KeyPair,
+    ) -> rustls::ServerConfig {
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(key.serialize_der());
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key.into())
+            .unwrap()
+    }
+
+    /// A server config presenting a fresh self-signed certificate for
+    /// `localhost`.
+    fn localhost_config() -> rustls::ServerConfig {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        server_config(&cert, &key_pair)
+    }
+
+    /// Spawns a TLS server on loopback with a self-signed certificate for
+    /// `localhost`. See [`serve_once_with`].
+    fn serve_once(response: &'static [u8]) -> (SocketAddr, JoinHandle<String>) {
+        serve_once_with(localhost_config(), response)
+    }
+
+    /// Like [`serve_once`], but serves `connections` connections in turn.
+    fn serve(
+        response: &'static [u8],
+        connections: usize,
+    ) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        serve_with(localhost_config(), response, connections)
+    }
+
+    /// Spawns a TLS server on loopback that accepts a single connection,
+    /// answers it with `response` and hands back the request line it read, or
+    /// an empty string if the exchange failed.
+    fn serve_once_with(
+        config: rustls::ServerConfig,
+        response: &'static [u8],
+    ) -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle =
+            std::thread::spawn(move || exchange(&listener, Arc::new(config), response));
+        (addr, handle)
+    }
+
+    /// Like [`serve_once_with`], but serves `connections` connections in turn.
+    fn serve_with(
+        config: rustls::ServerConfig,
+        response: &'static [u8],
+        connections: usize,
+    ) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        serve_each(config, vec![response; connections])
+    }
+
+    /// Like [`serve_with`], but answers each connection with the next of
+    /// `responses`.
+    fn serve_each(
+        config: rustls::ServerConfig,
+        responses: Vec<&'static [u8]>,
+    ) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(config);
+        let handle = std::thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| exchange(&listener, config.clone(), response))
+                .collect()
+        });
+        (addr, handle)
+    }
+
+    fn exchange(
+        listener: &TcpListener,
+        config: Arc<rustls::ServerConfig>,
+        response: &[u8],
+    ) -> String {
+        let (sock, _) = listener.accept().unwrap();
+        let conn = rustls::ServerConnection::new(config).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, sock);
+        let mut line = Vec::new();
+        let mut exchange = |line: &mut Vec<u8>| -> io::Result<()> {
+            while !line.ends_with(b"\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte)?;
+                line.push(byte[0]);
+            }
+            stream.write_all(response)?;
+            stream.conn.send_close_notify();
+            stream.flush()
+        };
+        match exchange(&mut line) {
+            Ok(()) => String::from_utf8(line).unwrap(),
+            Err(_) => String::new(),
+        }
+    }
+
+    #[test]
+    fn stack_str() {
+        let meta: StackStr<1024> = "text/gemini".parse().unwrap();
+        assert_eq!(meta.as_str(), "text/gemini");
+        assert_eq!(&*StackStr::<3>::try_from("abc").unwrap(), "abc");
+        assert!("a".repeat(1025).parse::<StackStr<1024>>().is_err());
+        assert!(StackStr::<3>::try_from("abcd").is_err());
+    }
+
+    #[test]
+    fn response_header_parse() {
+        // https://geminiprotocol.net/docs/tech-overview.gmi
+        // 3.1 Response headers
+        //
+        // > <STATUS><SPACE><META><CR><LF>
+        let header = ResponseHeader::parse("20 all good, yup\r\n").unwrap();
+        assert_eq!(header.status, Status::Success);
+        assert_eq!(header.meta(), "all good, yup");
+        assert!(ResponseHeader::parse("59 missing-crlf").is_err());
+        assert!(ResponseHeader::parse("59-missing-space\r\n").is_err());
+        assert!(ResponseHeader::parse("69 bad number\r\nf").is_err());
+        // non UTF-8
+        assert!(
+            ResponseHeader::parse(b"\xF0\xA4\xAD\xA2\xF0\xA4\xAD\xA2\xF0\xA4\xAD")
+                .is_err()
+        );
+
+        // A well-formed two digit number that isn't a Gemini status is a status
+        // error, not a malformed header.
+        let err = ResponseHeader::parse("05 meta\r\n").unwrap_err();
+        assert!(matches!(err, ResponseHeaderParseError::Status(_)));
+        assert!(err.to_string().contains("05 is outside the range"));
+
+        let meta = "too large <META>".repeat(100);
+        assert!(ResponseHeader::parse(format!("20 {meta}\r\n")).is_err());
+        let meta = "stuff after a CRLF doesn't get touched\r\n".repeat(50);
+        assert!(ResponseHeader::parse(format!("20 {meta}")).is_ok());
+    }
+
+    #[test]
+    fn response_header_control_characters() {
+        for src in [
+            "20 text/\rgemini\r\n",
+            "10 two\nlines\r\n",
+            "30 a\tb\r\n",
+            "51 \0\r\n",
+        ] {
+            assert!(
+                matches!(
+                    ResponseHeader::parse(src),
+                    Err(ResponseHeaderParseError::Malformed(_))
+                ),
+                "{src:?}"
+            );
+            let meta = &src[3..src.len() - 2];
+            assert_eq!(ResponseHeader::parse_lenient(src).unwrap().meta(), meta);
+            assert!(ResponseHeader::new(Status::Success, meta).is_err());
+        }
+        assert!(ResponseHeader::parse_lenient("20 \u{FEFF}\r\n").is_err());
+        assert!(ResponseHeader::parse("20 \r\n").is_ok());
+    }
+
+    #[test]
+    fn response_header_write() {
+        for src in [
+            "20 text/gemini; charset=utf-8\r\n",
+            "20 \r\n",
+            "10 What's your name?\r\n",
+            "31 gemini://example.com/new\r\n",
+            "62 Certificate not valid\r\n",
+        ] {
+            let mut out = Vec::new();
+            ResponseHeader::parse(src).unwrap().write(&mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), src);
+        }
+        let header =
+            ResponseHeader::new(Status::try_from(44).unwrap(), &"9".repeat(1024))
+                .unwrap();
+        let mut out = Vec::new();
+        header.write(&mut out).unwrap();
+        assert_eq!(out.len(), 2 + 1 + 1024 + 2);
+        assert_eq!(ResponseHeader::parse(&out).unwrap().meta(), header.meta());
+        assert!(matches!(
+            ResponseHeader::new(Status::Success, &"9".repeat(1025)),
+            Err(ResponseHeaderParseError::Malformed(_))
+        ));
+        assert!(matches!(
+            ResponseHeader::new(Status::Success, "\u{FEFF}text/gemini"),
+            Err(ResponseHeaderParseError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn response_parse() {
+        let text = "20 text/gemini; charset=utf-8\r\nthis is some text\r\n, pretend this is markdown or something".to_string();
+        let reader = std::io::BufReader::new(Cursor::new(text.as_bytes().to_vec()));
+        let response = Response::read(reader).unwrap();
+        assert_eq!(response.header.status, Status::Success);
+        assert_eq!(response.header.meta(), "text/gemini; charset=utf-8");
+        assert!(std::str::from_utf8(response.body.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn titan_request() {
+        let request = TitanRequest::new("titan://example.com/a.gmi")
+            .unwrap()
+            .mime("text/plain")
+            .token("s3cr t");
+        assert_eq!(
+            request.line(5).unwrap(),
+            "titan://example.com/a.gmi;size=5;mime=text/plain;token=s3cr%20t"
+        );
+        assert_eq!(
+            TitanRequest::new("TITAN://example.com/")
+                .unwrap()
+                .line(0)
+                .unwrap(),
+            "TITAN://example.com/;size=0"
+        );
+        assert_eq!(
+            TitanRequest::new("titan://example.com/a?x=1")
+                .unwrap()
+                .mime("text/plain")
+                .line(3)
+                .unwrap(),
+            "titan://example.com/a;size=3;mime=text/plain?x=1"
+        );
+        assert_eq!(
+            TitanRequest::new("titan://example.com:1966?x")
+                .unwrap()
+                .line(1)
+                .unwrap(),
+            "titan://example.com:1966/;size=1?x"
+        );
+        assert!(matches!(
+            TitanRequest::new("gemini://example.com/"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::WrongScheme))
+        ));
+        assert!(matches!(
+            TitanRequest::new("//example.com/"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::WrongScheme))
+        ));
+        assert!(matches!(
+            TitanRequest::new("titan://example.com/#frag"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::Fragment))
+        ));
+        let long = TitanRequest::new(format!("titan://example.com/{}", "a".repeat(1000)))
+            .unwrap()
+            .token(&"t".repeat(100));
+        assert!(matches!(long.line(1), Err(RequestError::UrlTooLong)));
+    }
+
+    #[test]
+    fn client_send_titan() {
+        let config = Arc::new(localhost_config());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            let mut line = Vec::new();
+            while !line.ends_with(b"\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                line.push(byte[0]);
+            }
+            let line = String::from_utf8(line).unwrap();
+            let size = line
+                .split(';')
+                .find_map(|x| x.strip_prefix("size="))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; size];
+            stream.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"30 gemini://localhost/page.gmi\r\n")
+                .unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            (line, body)
+        });
+        let request = TitanRequest::new(format!("titan://localhost:{port}/page.gmi"))
+            .unwrap()
+            .mime("text/gemini");
+        let response = Client::new().send_titan(request, b"# Hi\n").unwrap();
+        assert_eq!(response.header.meta(), "gemini://localhost/page.gmi");
+        let (line, body) = server.join().unwrap();
+        assert_eq!(
+            line,
+            format!("titan://localhost:{port}/page.gmi;size=5;mime=text/gemini\r\n")
+        );
+        assert_eq!(body, b"# Hi\n");
+    }
+
+    #[test]
+    fn request_from_input() {
+        let base = uri::Uri::new("gemini://example.com/search?old#frag").unwrap();
+        let request = Request::from_input(&base, "cats & dogs").unwrap();
+        assert_eq!(
+            request.url_as_str(),
+            "gemini://example.com/search?cats%20%26%20dogs"
+        );
+        assert_eq!(request.search_query().as_deref(), Some("cats & dogs"));
+        let request = request.with_query("50%").unwrap();
+        assert_eq!(request.url_as_str(), "gemini://example.com/search?50%25");
+    }
+
+    #[test]
+    fn client_connect_exchange() {
+        let (addr, server) = serve_once(b"20 text/gemini\r\n# Hello\n");
+        let mut conn = Client::new().connect("localhost", addr.port()).unwrap();
+        let request =
+            Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap();
+        conn.send(request).unwrap();
+        let response = conn.read_response().unwrap();
+        assert_eq!(response.header.status, Status::Success);
+        assert_eq!(response.header.meta(), "text/gemini");
+        assert_eq!(response.body_as_str().unwrap(), "# Hello\n");
+        assert_eq!(
+            server.join().unwrap(),
+            format!("gemini://localhost:{}/\r\n", addr.port())
+        );
+    }
+
+    #[test]
+    fn client_ipv6_literal() {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["::1".to_string()]).unwrap();
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Arc::new(server_config(&cert, &key_pair));
+        let server =
+            std::thread::spawn(move || exchange(&listener, config, b"20 \r\nok"));
+        let url = format!("gemini://[::1]:{port}/");
+        let response = Client::new()
+            .send_request(Request::new(&url).unwrap())
+            .unwrap();
+        assert_eq!(response.body_as_str().unwrap(), "ok");
+        assert_eq!(server.join().unwrap(), format!("{url}\r\n"));
+    }
+
+    #[test]
+    fn client_send_request_to_address() {
+        let (addr, server) = serve_once(b"20 text/gemini\r\nstaging");
+        let request = Request::new("gemini://staging.example.com/page.gmi").unwrap();
+        let response = Client::new().send_request_to(addr, request).unwrap();
+        assert_eq!(response.body_as_str().unwrap(), "staging");
+        assert_eq!(
+            server.join().unwrap(),
+            "gemini://staging.example.com/page.gmi\r\n"
+        );
+    }
+
+    #[test]
+    fn client_with_root_store() {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client = Client::builder().with_root_store(roots).build().unwrap();
+        let request = || Request::new("gemini://localhost/").unwrap();
+
+        // A certificate issued by the CA is trusted...
+        let leaf_key = rcgen::KeyPair::generate().unwrap();
+        let leaf = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&leaf_key, &ca, &ca_key)
+            .unwrap();
+        let (addr, _) = serve_once_with(server_config(&leaf, &leaf_key), b"20 \r\nok");
+        assert!(client.send_request_to(addr, request()).is_ok());
+
+        // ...but a self-signed one is not.
+        let (addr, server) = serve_once(b"20 \r\nok");
+        assert!(client.send_request_to(addr, request()).is_err());
+        assert_eq!(server.join().unwrap(), "");
+    }
+
+    #[test]
+    fn client_sni_override() {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client = Client::builder().with_root_store(roots).build().unwrap();
+        let leaf_key = rcgen::KeyPair::generate().unwrap();
+        let leaf = rcgen::CertificateParams::new(vec!["capsule.example".to_string()])
+            .unwrap()
+            .signed_by(&leaf_key, &ca, &ca_key)
+            .unwrap();
+        let request = || Request::new("gemini://127.0.0.1/page.gmi").unwrap();
+
+        // The certificate isn't for the address in the URL...
+        let (addr, server) =
+            serve_once_with(server_config(&leaf, &leaf_key), b"20 \r\nok");
+        let err = client.send_request_to(addr, request()).unwrap_err();
+        assert!(matches!(err, ClientError::Handshake(_)), "{err:?}");
+        assert_eq!(server.join().unwrap(), "");
+
+        // ...but it is for the name sent instead.
+        let (addr, server) =
+            serve_once_with(server_config(&leaf, &leaf_key), b"20 \r\nok");
+        let response = client
+            .send_request_with_sni(addr, request(), "capsule.example")
+            .unwrap();
+        assert_eq!(response.body, b"ok");
+        assert_eq!(server.join().unwrap(), "gemini://127.0.0.1/page.gmi\r\n");
+        assert!(matches!(
+            client.send_request_with_sni(addr, request(), "not a name"),
+            Err(ClientError::BadHost)
+        ));
+    }
+
+    #[test]
+    fn client_with_expiry_checks() {
+        let client = Client::with_expiry_checks();
+        let (addr, _) = serve_once(b"20 \r\nok");
+        let response = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(response.body, b"ok");
+
+        let (addr, server) = serve_once(b"20 \r\nok");
+        let err = client
+            .send_request_with_sni(
+                addr,
+                Request::new("gemini://localhost/").unwrap(),
+                "example.com",
+            )
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ClientError::Handshake(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::NotValidForName
+                ))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(server.join().unwrap(), "");
+    }
+
+    #[test]
+    fn client_with_tofu_verifier() {
+        let store = Arc::new(tofu::MemoryStore::new());
+        let client =
+            Client::with_verifier(Arc::new(tofu::TofuVerifier::new(store.clone())));
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let request = || Request::new("gemini://localhost/").unwrap();
+
+        // The first certificate seen is pinned and keeps being accepted...
+        let (addr, _) = serve_with(server_config(&cert, &key_pair), b"20 \r\nok", 2);
+        assert!(client.send_request_to(addr, request()).is_ok());
+        assert!(client.send_request_to(addr, request()).is_ok());
+        assert_eq!(
+            tofu::TofuStore::get(&*store, "localhost"),
+            Some(tofu::fingerprint(cert.der()))
+        );
+
+        // ...but a different one for the same host is refused.
+        let (addr, server) = serve_once(b"20 \r\nok");
+        assert!(client.send_request_to(addr, request()).is_err());
+        assert_eq!(server.join().unwrap(), "");
+    }
+
+    #[test]
+    fn client_probe() {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (addr, server) = serve_once_with(server_config(&cert, &key_pair), b"20 \r\n");
+        let client = Client::new();
+        let probe = client.probe("localhost", addr.port()).unwrap();
+        assert_eq!(client.handshake_stats().handshakes, 1);
+        assert!(matches!(
+            probe.version,
+            rustls::ProtocolVersion::TLSv1_2 | rustls::ProtocolVersion::TLSv1_3
+        ));
+        assert_ne!(
+            probe.cipher_suite,
+            rustls::CipherSuite::TLS_NULL_WITH_NULL_NULL
+        );
+        assert_eq!(probe.certificates, [cert.der().clone()]);
+        assert_eq!(
+            probe.subject().as_deref(),
+            Some("CN=rcgen self signed cert")
+        );
+        assert!(probe.expiry().is_some());
+        assert_eq!(probe.fingerprint(), Some(tofu::fingerprint(cert.der())));
+        // No request was sent.
+        assert_eq!(server.join().unwrap(), "");
+    }
+
+    #[test]
+    fn client_handshake_error_vs_failure_status() {
+        let client = Client::builder()
+            .with_root_store(rustls::RootCertStore::empty())
+            .build()
+            .unwrap();
+        let (addr, server) = serve_once(b"51 Not found\r\n");
+        let err = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ClientError::Handshake(rustls::Error::InvalidCertificate(_))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(server.join().unwrap(), "");
+
+        let (addr, server) = serve_once(b"51 Not found\r\n");
+        let response = Client::new()
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(
+            response.header.status,
+            Status::PermanentFailure(status::PermanentFailure::NotFound)
+        );
+        assert_eq!(response.header.meta(), "Not found");
+        assert_eq!(server.join().unwrap(), "gemini://localhost/\r\n");
+    }
+
+    #[test]
+    fn client_identity() {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client_verifier =
+            rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .unwrap();
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+        let config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(vec![cert.der().clone()], key.into())
+            .unwrap();
+
+        let user_key = rcgen::KeyPair::generate().unwrap();
+        let user = rcgen::CertificateParams::new(Vec::<String>::new())
+            .unwrap()
+            .signed_by(&user_key, &ca, &ca_key)
+            .unwrap();
+        let certs = || vec![user.der().clone()];
+        let key = || {
+            PrivateKeyDer::from(rustls::pki_types::PrivatePkcs8KeyDer::from(
+                user_key.serialize_der(),
+            ))
+        };
+        let (addr, server) = serve_with(config, b"20 \r\nwelcome", 3);
+        let url = format!("gemini://localhost:{}/", addr.port());
+        let request = || Request::new(&url).unwrap();
+        assert!(Client::new().send_request(request()).is_err());
+        let client = Client::with_identity(certs(), key()).unwrap();
+        let response = client.send_request(request()).unwrap();
+        assert_eq!(response.body_as_str().unwrap(), "welcome");
+        let identity = Identity::new(certs(), key()).unwrap();
+        let response = Client::new().send_request_as(request(), &identity).unwrap();
+        assert_eq!(response.body_as_str().unwrap(), "welcome");
+        let line = format!("{url}\r\n");
+        assert_eq!(server.join().unwrap(), ["", &line, &line]);
+
+        // The key has to match the certificate.
+        let other_key = rcgen::KeyPair::generate().unwrap();
+        let other_key = PrivateKeyDer::from(rustls::pki_types::PrivatePkcs8KeyDer::from(
+            other_key.serialize_der(),
+        ));
+        assert!(Identity::new(certs(), other_key).is_err());
+    }
+
+    #[test]
+    fn client_read_timeout() {
+        // The connection is accepted by the OS, but nothing is ever sent back.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = Client::with_timeout(std::time::Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        let err = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Timeout), "{err:?}");
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn client_handshake_timeout() {
+        // The connection is accepted, but the server never says a word.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = Client::builder()
+            .handshake_timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let started = std::time::Instant::now();
+        let err = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Timeout), "{err:?}");
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn client_idle_timeout() {
+        let config = Arc::new(localhost_config());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Sends the header and a single byte of the body, then stalls while
+        // keeping the connection open.
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            let mut byte = [0];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            stream.write_all(b"20 text/plain\r\na").unwrap();
+            stream.flush().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        });
+
+        let client = Client::builder()
+            .idle_timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let started = std::time::Instant::now();
+        let err = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Timeout), "{err:?}");
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_idle_timeout_waits_for_header() {
+        let config = Arc::new(localhost_config());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Takes its time to answer, then sends the whole response at once.
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            let mut byte = [0];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            stream.write_all(b"20 text/plain\r\nok").unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+        });
+
+        let client = Client::builder()
+            .idle_timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let response = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(response.body, b"ok");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request_scheme() {
+        assert!(matches!(
+            Request::new("http://example.com/"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::WrongScheme))
+        ));
+        assert!(matches!(
+            Request::new("mailto:someone@example.com"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::WrongScheme))
+        ));
+        assert_eq!(
+            Request::new("GEMINI://example.com/").unwrap().url_as_str(),
+            "GEMINI://example.com/"
+        );
+        assert_eq!(
+            Request::new("//example.com/a").unwrap().url_as_str(),
+            "gemini://example.com/a"
+        );
+        // Defaulting the scheme can't push a URL past the limit.
+        let long = format!("//example.com/{}", "a".repeat(1024 - 14));
+        assert!(matches!(Request::new(&long), Err(RequestError::UrlTooLong)));
+        let long = uri::Uri::new(&long).unwrap();
+        assert!(matches!(
+            Request::try_from(long),
+            Err(RequestError::UrlTooLong)
+        ));
+    }
+
+    #[test]
+    fn request_from_uri() {
+        let url = uri::Uri::new("//example.com/a?b").unwrap();
+        let request = Request::try_from(url).unwrap();
+        assert_eq!(request.url_as_str(), "gemini://example.com/a?b");
+        let url = uri::UriOwned::from(uri::Uri::new("gemini://example.com/").unwrap());
+        assert_eq!(
+            Request::try_from(&url).unwrap().url_as_str(),
+            "gemini://example.com/"
+        );
+        let url = uri::Uri::new("https://example.com/").unwrap();
+        assert!(matches!(
+            Request::try_from(url),
+            Err(RequestError::NotGemini(uri::GeminiUriError::WrongScheme))
+        ));
+        let url = uri::Uri::new("gemini://example.com/#frag").unwrap();
+        assert!(matches!(
+            Request::try_from(url),
+            Err(RequestError::NotGemini(uri::GeminiUriError::Fragment))
+        ));
+        // Hand-built components can't smuggle anything into the request line.
+        let url = uri::UriOwned::from(uri::Uri::new("gemini://example.com/").unwrap());
+        for bad in [
+            uri::UriOwned {
+                path: Some("/a\r\nb".into()),
+                ..url.clone()
+            },
+            uri::UriOwned {
+                query: Some("q\r\n20 text/gemini".into()),
+                ..url.clone()
+            },
+            uri::UriOwned {
+                path: Some("/a b".into()),
+                ..url.clone()
+            },
+            uri::UriOwned {
+                host: Some("example.com\n".into()),
+                ..url
+            },
+        ] {
+            assert!(
+                matches!(Request::try_from(&bad), Err(RequestError::InvalidUrl)),
+                "{bad:?}"
+            );
         }
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-pub enum ClientError {
-    #[error("I/O: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("Response: {0}")]
-    Response(#[from] ResponseReadError),
-    #[error("Rustls: {0}")]
-    Rustls(#[from] rustls::Error),
-    #[error("Port is invalid")]
-    BadPort,
-}
+    #[test]
+    fn client_bad_host_and_port() {
+        let client = Client::new();
+        // An out of range port can't even make it into a request.
+        assert!(matches!(
+            Request::new("gemini://localhost:99999/"),
+            Err(RequestError::NotGemini(uri::GeminiUriError::BadPort))
+        ));
+        // Never resolved, as the address is given, but not a valid server name.
+        let (addr, _) = serve_once(b"20 \r\n");
+        assert!(matches!(
+            client.send_request_to(addr, Request::new("gemini://bad..host/").unwrap()),
+            Err(ClientError::BadHost)
+        ));
+    }
 
-pub struct Client {
-    cfg: Arc<rustls::client::ClientConfig>,
-}
+    #[test]
+    fn client_follows_redirects() {
+        let responses: Vec<&'static [u8]> =
+            vec![b"30 /b\r\n", b"31 c?q\r\n", b"20 text/plain\r\ndone"];
 
-impl Default for Client {
-    fn default() -> Self {
-        Self::new()
+        let (addr, server) = serve_each(localhost_config(), responses.clone());
+        let base = format!("gemini://localhost:{}", addr.port());
+        let response = Client::new()
+            .send_request_following(Request::new(format!("{base}/a")).unwrap(), 2)
+            .unwrap();
+        assert_eq!(response.body_as_str().unwrap(), "done");
+        assert_eq!(
+            response.url.map(|url| url.to_string()),
+            Some(format!("{base}/c?q"))
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                format!("{base}/a\r\n"),
+                format!("{base}/b\r\n"),
+                format!("{base}/c?q\r\n")
+            ]
+        );
+
+        let (addr, _) = serve_each(localhost_config(), responses[..2].to_vec());
+        let base = format!("gemini://localhost:{}", addr.port());
+        assert!(matches!(
+            Client::new()
+                .send_request_following(Request::new(format!("{base}/a")).unwrap(), 1),
+            Err(ClientError::TooManyRedirects(1))
+        ));
+
+        let (addr, _) = serve_each(localhost_config(), vec![b"30 /b\r\n", b"30 /a\r\n"]);
+        let base = format!("gemini://localhost:{}", addr.port());
+        match Client::new()
+            .send_request_following(Request::new(format!("{base}/a")).unwrap(), 5)
+        {
+            Err(ClientError::RedirectLoop(url)) => assert_eq!(url, format!("{base}/a")),
+            other => panic!("expected a redirect loop, got {other:?}"),
+        }
     }
-}
 
-impl Client {
-    pub fn new() -> Self {
-        let config = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(DummyVerifier))
-            .with_no_client_auth();
-        Self {
-            cfg: Arc::new(config),
+    #[test]
+    fn client_fatal_alerts() {
+        // A fatal handshake_failure alert in place of a ServerHello.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            // Read the whole ClientHello record so closing the socket doesn't
+            // reset the connection.
+            let mut header = [0; 5];
+            sock.read_exact(&mut header).unwrap();
+            let len = u16::from_be_bytes([header[3], header[4]]);
+            sock.read_exact(&mut vec![0; usize::from(len)]).unwrap();
+            sock.write_all(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28])
+                .unwrap();
+        });
+        let err = Client::new()
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ClientError::Handshake(rustls::Error::AlertReceived(
+                    rustls::AlertDescription::HandshakeFailure
+                ))
+            ),
+            "{err:?}"
+        );
+        server.join().unwrap();
+
+        // Garbage from the server once the handshake is done.
+        let config = Arc::new(localhost_config());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            let mut byte = [0];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            stream
+                .sock
+                .write_all(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28])
+                .unwrap();
+        });
+        let err = Client::new()
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Rustls(_)), "{err:?}");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_read_buffer_size() {
+        let body: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let response = [b"20 application/octet-stream\r\n".as_slice(), &body].concat();
+        let (addr, server) = serve_once(Vec::leak(response));
+        let client = Client::builder().read_buffer_size(7).build().unwrap();
+        let response = client
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(response.header.meta(), "application/octet-stream");
+        assert_eq!(response.body, body);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_lenient_headers() {
+        let (addr, server) = serve(b"10 Name:\t\r\n", 2);
+        let request = || Request::new("gemini://localhost/").unwrap();
+        assert!(matches!(
+            Client::new().send_request_to(addr, request()),
+            Err(ClientError::Response(ResponseReadError::HeaderParse(
+                ResponseHeaderParseError::Malformed(_)
+            )))
+        ));
+        let client = Client::builder().lenient_headers(true).build().unwrap();
+        let response = client.send_request_to(addr, request()).unwrap();
+        assert_eq!(response.header.meta(), "Name:\t");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_max_body_size() {
+        let (addr, server) = serve(b"20 text/plain\r\n0123456789", 2);
+        let request =
+            || Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap();
+        let client = Client::builder().max_body_size(10).build().unwrap();
+        assert_eq!(client.send_request(request()).unwrap().body.len(), 10);
+        let client = Client::builder().max_body_size(9).build().unwrap();
+        assert!(matches!(
+            client.send_request(request()),
+            Err(ClientError::Response(ResponseReadError::BodyTooLarge(9)))
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_fetch_document() {
+        let fetch = |response: &'static [u8]| {
+            let (addr, _) = serve_once(response);
+            Client::new()
+                .fetch_document(&format!("gemini://localhost:{}/", addr.port()))
+                .unwrap()
+        };
+        assert_eq!(
+            fetch(b"20 text/gemini\r\n# Hi\n"),
+            FetchResult::Document(gemtext::Document::new("# Hi\n"))
+        );
+        assert_eq!(
+            fetch(b"11 Password\r\n"),
+            FetchResult::Input {
+                prompt: "Password".to_string(),
+                sensitive: true
+            }
+        );
+        assert_eq!(
+            fetch(b"20 image/png\r\n\x89PNG"),
+            FetchResult::Blob {
+                mime: "image/png".to_string(),
+                body: b"\x89PNG".to_vec()
+            }
+        );
+        assert_eq!(
+            fetch(b"51 Nothing here\r\n"),
+            FetchResult::Failure {
+                status: Status::PermanentFailure(status::PermanentFailure::NotFound),
+                meta: "Nothing here".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn client_reads_full_body() {
+        let body = "0123456789abcdef".repeat(64 * 1024);
+        let response: &'static [u8] =
+            format!("20 text/plain\r\n{body}").into_bytes().leak();
+        let (addr, _) = serve_once(response);
+        let response = Client::new()
+            .send_request(
+                Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(response.body.len(), body.len());
+        assert_eq!(response.body_as_str().unwrap(), body);
+        assert!(!response.truncated);
+    }
+
+    #[test]
+    fn client_reports_truncation() {
+        let config = Arc::new(localhost_config());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Hangs up without a close_notify.
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            let mut byte = [0];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            stream.write_all(b"20 text/plain\r\npart").unwrap();
+            stream.flush().unwrap();
+        });
+
+        let response = Client::new()
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(response.body, b"part");
+        assert!(response.truncated);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_resumes_sessions() {
+        let (addr, _) = serve(b"20 \r\nok", 2);
+        let client = Client::new();
+        let request =
+            || Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap();
+        client.send_request(request()).unwrap();
+        assert_eq!(
+            client.handshake_stats(),
+            HandshakeStats {
+                handshakes: 1,
+                resumed: 0
+            }
+        );
+        client.send_request(request()).unwrap();
+        let stats = client.handshake_stats();
+        assert_eq!(
+            stats,
+            HandshakeStats {
+                handshakes: 2,
+                resumed: 1
+            }
+        );
+        assert_eq!(stats.resumption_rate(), 0.5);
+    }
+
+    #[test]
+    fn client_session_cache() {
+        let (addr, server) = serve(b"20 \r\nok", 4);
+        let request =
+            || Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap();
+        for (size, resumed) in [(0, 0), (32, 1)] {
+            let client = Client::with_session_cache(size);
+            client.send_request(request()).unwrap();
+            client.send_request(request()).unwrap();
+            assert_eq!(
+                client.handshake_stats(),
+                HandshakeStats {
+                    handshakes: 2,
+                    resumed
+                },
+                "{size}"
+            );
         }
+        server.join().unwrap();
     }
 
-    pub fn send_request(&self, r: Request) -> Result<Response, ClientError> {
-        use std::net::TcpStream;
-        let url = r.url();
-        let host = url.host.unwrap();
-        let port = url.port.unwrap_or("1965").parse::<u16>().unwrap();
-        let mut cc = rustls::ClientConnection::new(
-            self.cfg.clone(),
-            ServerName::try_from(host).unwrap().to_owned(),
-        )?;
-        let mut sock = TcpStream::connect((host, port))?;
-
-        // 1. Request TLS Session
-        cc.write_tls(&mut sock).unwrap();
-        // 2. Received Server Certificate
-        cc.read_tls(&mut sock).unwrap();
-        // 3. Check certificate
-        cc.process_new_packets().unwrap();
-        // 4. Write out request
-        r.write(cc.writer()).unwrap();
-        // 5. Encrypt request and flush
-        cc.write_tls(&mut sock).unwrap();
-        let mut closed = false;
-        let mut data = Vec::new();
-        while !closed {
-            while cc.wants_read() && !closed {
-                cc.read_tls(&mut sock).unwrap();
-                let state = cc.process_new_packets().unwrap();
-                closed = state.peer_has_closed();
+    #[test]
+    fn client_clones_share_sessions() {
+        let (addr, server) = serve(b"20 \r\nok", 2);
+        let request =
+            Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap();
+        let client = Client::default();
+        client.send_request(request).unwrap();
+        let clone = client.clone();
+        std::thread::spawn(move || clone.send_request(request).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(
+            client.handshake_stats(),
+            HandshakeStats {
+                handshakes: 2,
+                resumed: 1
+            }
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_sets_peer_certificate() {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (addr, _) = serve_once_with(server_config(&cert, &key_pair), b"20 \r\n");
+        let response = Client::new()
+            .send_request_to(addr, Request::new("gemini://localhost/").unwrap())
+            .unwrap();
+        assert_eq!(response.peer_certificate.as_ref(), Some(cert.der()));
+        assert_eq!(
+            response.fingerprint_sha256(),
+            Some(tofu::fingerprint(cert.der()))
+        );
+        let response = Response::from_bytes(b"20 \r\n").unwrap();
+        assert_eq!(response.peer_certificate, None);
+        assert_eq!(response.fingerprint_sha256(), None);
+    }
+
+    #[test]
+    fn client_sets_received_at() {
+        let (addr, _) = serve_once(b"20 text/gemini\r\nhi");
+        let before = std::time::SystemTime::now();
+        let response = Client::new()
+            .send_request(
+                Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap(),
+            )
+            .unwrap();
+        let received_at = response.received_at.unwrap();
+        assert!(received_at >= before);
+        assert!(received_at.elapsed().unwrap() < std::time::Duration::from_secs(5));
+        assert!(
+            Response::read(Cursor::new(b"20 text/gemini\r\n"))
+                .unwrap()
+                .received_at
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn response_into_gemtext_document() {
+        let response = Response::read(Cursor::new(
+            b"20 text/gemini; lang=en\r\n# Title\n=> /a A\n",
+        ))
+        .unwrap();
+        let document = response.into_gemtext_document().unwrap();
+        assert_eq!(
+            document.tokens(),
+            [
+                gemtext::GemtextTokenOwned::Heading("Title".to_string(), 1),
+                gemtext::GemtextTokenOwned::Link("/a".to_string(), Some("A".to_string())),
+            ]
+        );
+        let response =
+            Response::read(Cursor::new(b"20 text/plain\r\n# Title\n")).unwrap();
+        assert!(response.into_gemtext_document().is_none());
+        let response = Response::read(Cursor::new(b"51 Not found\r\n")).unwrap();
+        assert!(response.into_gemtext_document().is_none());
+    }
+
+    #[test]
+    fn media_type() {
+        let media_type =
+            MediaType::parse("Text/Gemini; charset=UTF-8;lang=\"en\"").unwrap();
+        assert_eq!(media_type.type_, "text");
+        assert_eq!(media_type.subtype, "gemini");
+        assert_eq!(media_type.parameter("Charset"), Some("UTF-8"));
+        assert_eq!(media_type.parameter("lang"), Some("en"));
+        assert_eq!(
+            media_type.to_string(),
+            "text/gemini; charset=UTF-8; lang=en"
+        );
+        assert!(MediaType::parse("").is_none());
+        assert!(MediaType::parse("text").is_none());
+
+        let extension = |src| MediaType::parse(src).unwrap().preferred_extension();
+        assert_eq!(extension("image/png"), Some("png"));
+        assert_eq!(extension("application/x-unknown"), None);
+    }
+
+    #[test]
+    fn response_header_mime() {
+        let mime = |src: &str| ResponseHeader::parse(src).unwrap().mime();
+        let gemtext = mime("20 \r\n").unwrap();
+        assert!(gemtext.is_gemtext());
+        assert_eq!(gemtext.charset(), Some("utf-8"));
+        assert_eq!(gemtext.lang(), None);
+
+        let text = mime("20 text/plain; charset=ISO-8859-1; lang=en\r\n").unwrap();
+        assert!(text.is_text() && !text.is_gemtext());
+        assert_eq!(
+            (text.type_.as_str(), text.subtype.as_str()),
+            ("text", "plain")
+        );
+        assert_eq!(text.charset(), Some("ISO-8859-1"));
+        assert_eq!(text.lang(), Some("en"));
+        assert_eq!(mime("20 text/gemini\r\n").unwrap().charset(), Some("utf-8"));
+        assert_eq!(mime("20 image/png\r\n").unwrap().charset(), None);
+
+        assert_eq!(mime("51 text/gemini\r\n"), None);
+        assert_eq!(mime("20 nonsense\r\n"), None);
+    }
+
+    #[test]
+    fn response_decoded_body() {
+        let decode = |src: &[u8]| {
+            Response::from_bytes(src)
+                .unwrap()
+                .decoded_body()
+                .into_owned()
+        };
+        assert_eq!(
+            decode(b"20 text/plain; charset=ISO-8859-1\r\ncaf\xE9"),
+            "café"
+        );
+        assert_eq!(decode(b"20 text/plain; charset=latin1\r\n\xA9"), "©");
+        assert_eq!(
+            decode(b"20 text/plain; charset=us-ascii\r\nab\xE9"),
+            "ab\u{FFFD}"
+        );
+        assert_eq!(decode(b"20 text/gemini\r\ncaf\xC3\xA9"), "café");
+        assert_eq!(decode(b"20 \r\ncaf\xE9"), "caf\u{FFFD}");
+        assert_eq!(decode(b"20 text/plain; charset=koi8-r\r\nok"), "ok");
+        assert!(matches!(
+            Response::from_bytes(b"20 text/plain\r\nok")
+                .unwrap()
+                .decoded_body(),
+            Cow::Borrowed("ok")
+        ));
+    }
+
+    #[test]
+    fn response_into_result() {
+        let result = |src: &[u8]| Response::from_bytes(src).unwrap().into_result();
+        assert_eq!(result(b"20 text/plain\r\nok").unwrap().body, b"ok");
+        assert!(result(b"10 Name?\r\n").is_ok());
+        let err = result(b"51 Nothing here\r\n").unwrap_err();
+        assert_eq!(
+            err,
+            ResponseFailure::Failure {
+                status: Status::PermanentFailure(status::PermanentFailure::NotFound),
+                meta: "Nothing here".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "51 Not Found: Nothing here");
+        assert_eq!(
+            result(b"31 gemini://example.com/new\r\n").unwrap_err(),
+            ResponseFailure::Redirect {
+                status: Status::Redirect(status::Redirect::Permanent),
+                meta: "gemini://example.com/new".to_string()
+            }
+        );
+        assert!(matches!(
+            result(b"60 Who are you?\r\n"),
+            Err(ResponseFailure::Failure { .. })
+        ));
+    }
+
+    #[test]
+    fn response_same_content() {
+        let response = |src: &[u8]| Response::from_bytes(src).unwrap();
+        let mut first = response(b"20 text/gemini\r\n# Feed\n");
+        first.received_at = Some(std::time::SystemTime::UNIX_EPOCH);
+        let second = response(b"20 text/gemini\r\n# Feed\n");
+        assert!(first.same_content(&second));
+        assert!(!first.same_content(&response(b"20 text/gemini\r\n# Feed!\n")));
+        assert!(!first.same_content(&response(b"20 text/plain\r\n# Feed\n")));
+        assert!(!first.same_content(&response(b"40 text/gemini\r\n# Feed\n")));
+    }
+
+    #[test]
+    fn response_verify_length() {
+        let response = Response::from_bytes(b"20 text/plain\r\nhello").unwrap();
+        assert_eq!(response.verify_length(5), Ok(()));
+        let err = response.verify_length(6).unwrap_err();
+        assert_eq!(
+            err,
+            BodyLengthError {
+                expected: 6,
+                actual: 5
             }
-            let _ = cc.reader().read_to_end(&mut data);
+        );
+        assert_eq!(err.to_string(), "Expected a body of 6 bytes, but got 5");
+    }
+
+    #[test]
+    fn response_suggested_filename() {
+        let response = |src: &[u8]| Response::from_bytes(src).unwrap();
+        let url = |src| uri::Uri::new(src).unwrap();
+        let png = response(b"20 image/png\r\n");
+        assert_eq!(
+            png.suggested_filename(&url("gemini://example.com/pics/cat")),
+            "cat.png"
+        );
+        assert_eq!(
+            png.suggested_filename(&url("gemini://example.com/render.cgi?x")),
+            "render.png"
+        );
+        assert_eq!(
+            png.suggested_filename(&url("gemini://example.com/a/")),
+            "index.png"
+        );
+        assert_eq!(
+            png.suggested_filename(&url("gemini://example.com/..%2F.bashrc")),
+            ".._.png"
+        );
+        let unknown = response(b"20 application/x-unknown\r\n");
+        assert_eq!(
+            unknown.suggested_filename(&url("gemini://example.com/data.bin")),
+            "data.bin"
+        );
+        let not_found = response(b"51 Not found\r\n");
+        assert_eq!(not_found.media_type(), None);
+    }
+
+    #[test]
+    fn status_code_round_trip() {
+        let known = (0..=u8::MAX)
+            .filter_map(|code| Some((code, Status::try_from(code).ok()?)))
+            .collect::<Vec<_>>();
+        assert_eq!(known.len(), 18);
+        for (code, status) in known {
+            assert_eq!(status.code(), code);
+            assert_eq!(u8::from(status), code);
         }
+        assert_eq!(Status::Redirect(status::Redirect::Permanent).code(), 31);
+        assert_eq!(
+            Status::TemporaryFailure(status::TemporaryFailure::SlowDown).code(),
+            44
+        );
+    }
 
-        Ok(Response::read(std::io::Cursor::new(data))?)
+    #[test]
+    fn status_display() {
+        assert_eq!(Status::try_from(44).unwrap().to_string(), "44 Slow Down");
+        assert_eq!(Status::try_from(51).unwrap().to_string(), "51 Not Found");
+        assert_eq!(
+            Status::try_from(11).unwrap().to_string(),
+            "11 Sensitive Input"
+        );
+        assert_eq!(Status::Success.to_string(), "20 Success");
+        let header = ResponseHeader::parse("51 Gone fishing\r\n").unwrap();
+        assert_eq!(header.to_string(), "51 Gone fishing");
     }
-}
-#[derive(Debug)]
-struct DummyVerifier;
 
-use rustls::client::danger::{
-    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
-};
-use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
-use rustls::{DigitallySignedStruct, SignatureScheme};
-impl ServerCertVerifier for DummyVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &CertificateDer<'_>,
-        _intermediates: &[CertificateDer<'_>],
-        _server_name: &ServerName<'_>,
-        _ocsp_response: &[u8],
-        _now: UnixTime,
-    ) -> Result<ServerCertVerified, rustls::Error> {
-        Ok(ServerCertVerified::assertion())
+    #[test]
+    fn status_default_meta() {
+        assert_eq!(Status::Success.default_meta(), "text/gemini");
+        assert_eq!(Status::try_from(51).unwrap().default_meta(), "Not found");
+        assert_eq!(Status::try_from(44).unwrap().default_meta(), "Slow down");
+        assert_eq!(
+            Status::try_from(60).unwrap().default_meta(),
+            "Client certificate required"
+        );
     }
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
+
+    #[test]
+    fn status_category() {
+        for code in [10, 11, 20, 30, 31, 40, 44, 50, 59, 60, 62] {
+            let status = Status::try_from(code).unwrap();
+            assert_eq!(status.category(), code / 10);
+            let predicates = [
+                status.is_input(),
+                status.is_success(),
+                status.is_redirect(),
+                status.is_temporary_failure(),
+                status.is_permanent_failure(),
+                status.is_client_cert_required(),
+            ];
+            let expected: Vec<_> = (1..=6).map(|x| x == code / 10).collect();
+            assert_eq!(predicates[..], expected[..], "{code}");
+        }
     }
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
+
+    #[test]
+    fn response_header_length_guard() {
+        // A server that never stops sending header bytes.
+        let err = Response::read(io::repeat(b'a')).unwrap_err();
+        assert!(matches!(
+            err,
+            ResponseReadError::HeaderParse(ResponseHeaderParseError::Malformed(
+                "header too long"
+            ))
+        ));
+
+        let meta = "a".repeat(1024);
+        let mut reader = Cursor::new(format!("20 {meta}\r\nbody"));
+        let header = ResponseHeader::read(&mut reader).unwrap();
+        assert_eq!(header.meta(), meta);
+        assert_eq!(reader.position(), 2 + 1 + 1024 + 2);
     }
-    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        use rustls::SignatureScheme::*;
-        vec![
-            RSA_PKCS1_SHA1,
-            ECDSA_SHA1_Legacy,
-            RSA_PKCS1_SHA256,
-            ECDSA_NISTP256_SHA256,
-            RSA_PKCS1_SHA384,
-            ECDSA_NISTP384_SHA384,
-            RSA_PKCS1_SHA512,
-            ECDSA_NISTP521_SHA512,
-            RSA_PSS_SHA256,
-            RSA_PSS_SHA384,
-            RSA_PSS_SHA512,
-            ED25519,
-            ED448,
-        ]
+
+    /// A reader that hands out its chunks one read at a time, like a socket
+    /// receiving separate TLS records.
+    struct Chunked(std::collections::VecDeque<&'static [u8]>);
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.0.push_front(&chunk[n..]);
+            }
+            Ok(n)
+        }
     }
-}
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
+
     #[test]
-    fn response_header_parse() {
-        // https://geminiprotocol.net/docs/tech-overview.gmi
-        // 3.1 Response headers
-        //
-        // > <STATUS><SPACE><META><CR><LF>
-        let header = ResponseHeader::parse("20 all good, yup\r\n").unwrap();
-        assert_eq!(header.status, Status::Success);
-        assert_eq!(header.meta(), "all good, yup");
-        assert!(ResponseHeader::parse("59 missing-crlf").is_err());
-        assert!(ResponseHeader::parse("59-missing-space\r\n").is_err());
-        assert!(ResponseHeader::parse("69 bad number\r\nf").is_err());
-        // non UTF-8
-        assert!(
-            ResponseHeader::parse(b"\xF0\xA4\xAD\xA2\xF0\xA4\xAD\xA2\xF0\xA4\xAD")
-                .is_err()
+    fn response_header_split_across_reads() {
+        let chunks =
+            || Chunked([&b"20 text/gem"[..], b"ini\r", b"\nbody", b" more"].into());
+        let response = Response::read(chunks()).unwrap();
+        assert_eq!(response.header.meta(), "text/gemini");
+        assert_eq!(response.body, b"body more");
+
+        let (header, mut reader) = Response::read_streaming(chunks()).unwrap();
+        assert_eq!(header.meta(), "text/gemini");
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "body more");
+
+        let byte_at_a_time = Chunked(
+            b"51 Not found\r\n"
+                .iter()
+                .map(std::slice::from_ref)
+                .collect(),
         );
+        let response = Response::read(byte_at_a_time).unwrap();
+        assert_eq!(response.header.meta(), "Not found");
+        assert!(response.body.is_empty());
+    }
 
-        let meta = "too large <META>".repeat(100);
-        assert!(ResponseHeader::parse(format!("20 {meta}\r\n")).is_err());
-        let meta = "stuff after a CRLF doesn't get touched\r\n".repeat(50);
-        assert!(ResponseHeader::parse(format!("20 {meta}")).is_ok());
+    #[test]
+    fn response_read_streaming() {
+        let body = "0123456789".repeat(1000);
+        let data = format!("20 application/octet-stream\r\n{body}");
+        let (header, mut reader) = Response::read_streaming(Cursor::new(&data)).unwrap();
+        assert_eq!(header.status, Status::Success);
+        assert_eq!(header.meta(), "application/octet-stream");
+        let mut chunk = [0; 10];
+        reader.read_exact(&mut chunk).unwrap();
+        assert_eq!(&chunk, b"0123456789");
+        let mut out = Vec::new();
+        io::copy(&mut reader, &mut out).unwrap();
+        assert_eq!(out.len(), body.len() - 10);
+        assert!(matches!(
+            Response::read_streaming(Cursor::new(b"")),
+            Err(ResponseReadError::MissingHeader)
+        ));
     }
 
     #[test]
-    fn response_parse() {
-        let text = "20 text/gemini; charset=utf-8\r\nthis is some text\r\n, pretend this is markdown or something".to_string();
-        let reader = std::io::BufReader::new(Cursor::new(text.as_bytes().to_vec()));
-        let response = Response::read(reader).unwrap();
+    fn response_read_header_and_body_together() {
+        let data = b"20 text/gemini\r\nHELLO";
+        let response = Response::read(&data[..]).unwrap();
+        assert_eq!(response.header.meta(), "text/gemini");
+        assert_eq!(response.body, b"HELLO");
+        let (_, mut reader) = Response::read_streaming(&data[..]).unwrap();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "HELLO");
+        // The server sends the header and body with a single write, so they
+        // arrive in the same TLS record.
+        let (addr, _) = serve_once(data);
+        let response = Client::new()
+            .send_request(
+                Request::new(format!("gemini://localhost:{}/", addr.port())).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(response.body, b"HELLO");
+    }
+
+    #[test]
+    fn response_read_limited() {
+        let data = b"20 text/plain\r\nhello";
+        assert_eq!(Response::read_limited(&data[..], 5).unwrap().body, b"hello");
+        assert!(matches!(
+            Response::read_limited(&data[..], 4),
+            Err(ResponseReadError::BodyTooLarge(4))
+        ));
+        // An endless body is only read until it's past the limit.
+        let reader = b"20 \r\n".chain(io::repeat(b'a'));
+        assert!(matches!(
+            Response::read_limited(reader, 1024),
+            Err(ResponseReadError::BodyTooLarge(1024))
+        ));
+    }
+
+    #[test]
+    fn response_from_bytes() {
+        let response = Response::from_bytes(b"20 text/plain\r\nhello\r\nworld").unwrap();
         assert_eq!(response.header.status, Status::Success);
-        assert_eq!(response.header.meta(), "text/gemini; charset=utf-8");
-        assert!(std::str::from_utf8(response.body.as_slice()).is_ok());
+        assert_eq!(response.header.meta(), "text/plain");
+        assert_eq!(response.body, b"hello\r\nworld");
+        assert_eq!(response.body(), response.body.as_slice());
+        assert_eq!(response.as_ref(), b"hello\r\nworld");
+        assert!(matches!(
+            Response::from_bytes(b""),
+            Err(ResponseReadError::MissingHeader)
+        ));
+    }
+
+    #[test]
+    fn request_read_with_limit() {
+        let url = "gemini://example.com/a/long/path";
+        let mut reader = Cursor::new(format!("{url}\r\n").repeat(2));
+        assert!(matches!(
+            Request::read_with_limit(&mut reader, 16),
+            Err(RequestError::UrlOverLimit(16))
+        ));
+        // It stopped as soon as the limit was crossed.
+        assert_eq!(reader.position(), 17);
+
+        let read = Request::read_with_limit(Cursor::new(format!("{url}\r\n")), url.len());
+        assert_eq!(read.unwrap().url_as_str(), url);
+        let url = format!("gemini://example.com/{}", "a".repeat(1004));
+        assert!(matches!(
+            Request::read_with_limit(Cursor::new(format!("{url}\r\n")), 4096),
+            Err(RequestError::UrlTooLong)
+        ));
+    }
+
+    #[test]
+    fn request_read() {
+        let request = Request::new("gemini://example.com/a/b?c").unwrap();
+        let mut pipe = Vec::new();
+        request.write(&mut pipe).unwrap();
+        pipe.extend_from_slice(b"not part of the request");
+        let mut reader = Cursor::new(pipe);
+        let read = Request::read(&mut reader).unwrap();
+        assert_eq!(read.url_as_str(), request.url_as_str());
+        assert_eq!(reader.position(), request.url_as_str().len() as u64 + 2);
+
+        let url = format!("gemini://example.com/{}", "a".repeat(1003));
+        assert_eq!(url.len(), 1024);
+        assert!(Request::read(Cursor::new(format!("{url}\r\n"))).is_ok());
+        assert!(matches!(
+            Request::read(Cursor::new(format!("{url}a\r\n"))),
+            Err(RequestError::UrlTooLong)
+        ));
+        assert!(matches!(
+            Request::read(Cursor::new("a".repeat(4096))),
+            Err(RequestError::UrlTooLong)
+        ));
+        assert!(matches!(
+            Request::read(Cursor::new("\u{FEFF}gemini://example.com/\r\n")),
+            Err(RequestError::InvalidUrl)
+        ));
+        assert!(matches!(
+            Request::read(Cursor::new("/no/host\r\n")),
+            Err(RequestError::NotGemini(uri::GeminiUriError::MissingHost))
+        ));
+        assert!(matches!(
+            Request::read(Cursor::new("gemini://example.com/")),
+            Err(RequestError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn request_invalid_uri() {
+        assert!(matches!(
+            Request::new("gemini://example.com/\r\n20 injected"),
+            Err(RequestError::InvalidUri(uri::Error::Invalid))
+        ));
+        assert!(matches!(
+            Request::new(format!("gemini://example.com/{}", "a".repeat(1024))),
+            Err(RequestError::UrlTooLong)
+        ));
+    }
+
+    #[test]
+    fn request_search_query() {
+        let request = Request::new("gemini://example.com/path?hello%20world").unwrap();
+        assert_eq!(request.search_query().as_deref(), Some("hello world"));
+        let request = Request::new("gemini://example.com/path?").unwrap();
+        assert_eq!(request.search_query().as_deref(), Some(""));
+        let request = Request::new("gemini://example.com/path").unwrap();
+        assert_eq!(request.search_query(), None);
+        let request = Request::new("gemini://example.com/path?50%").unwrap();
+        assert_eq!(request.search_query().as_deref(), Some("50%"));
     }
 }